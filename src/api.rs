@@ -0,0 +1,199 @@
+//! JSON API for scripts and Node-RED, independent of HA conventions.
+use crate::consts::{InIdx, OutIdx, ProcIdx, ShutterIdx, Trigger};
+use crate::message::{args::OutputChangeRequest, Message, BROADCAST_ADDRESS};
+use crate::shutters;
+use serde::de::{Deserializer, Error};
+use serde::{Deserialize, Serialize};
+
+/// Message decoded from the bus, published with its source address.
+#[derive(Serialize)]
+pub struct Event<'a> {
+    pub addr: u8,
+    #[serde(flatten)]
+    pub message: &'a Message,
+}
+
+/// Command accepted on the API command topic, eg.
+/// `{"command": "set_output", "addr": 1, "output": 3, "state": "toggle"}`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    SetOutput {
+        #[serde(deserialize_with = "deserialize_addr")]
+        addr: u8,
+        output: OutIdx,
+        state: OutputChangeRequest,
+    },
+    TriggerInput {
+        #[serde(deserialize_with = "deserialize_addr")]
+        addr: u8,
+        input: InIdx,
+        trigger: Trigger,
+    },
+    CallProcedure {
+        #[serde(deserialize_with = "deserialize_addr")]
+        addr: u8,
+        proc_id: ProcIdx,
+    },
+    /// Shutter driver command, eg. `"open"` or `{"tilt": 50}`.
+    Shutter {
+        #[serde(deserialize_with = "deserialize_addr")]
+        addr: u8,
+        shutter: ShutterIdx,
+        cmd: shutters::Cmd,
+    },
+    RequestStatus {
+        #[serde(deserialize_with = "deserialize_addr")]
+        addr: u8,
+    },
+}
+
+/// Device address; the bus has 6 bits for it and would silently drop the
+/// rest, sending the command elsewhere.
+fn deserialize_addr<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    let addr = u8::deserialize(deserializer)?;
+    if addr > BROADCAST_ADDRESS {
+        return Err(D::Error::custom(format!(
+            "address {} is out of range (0-{})",
+            addr, BROADCAST_ADDRESS
+        )));
+    }
+    Ok(addr)
+}
+
+impl Command {
+    /// Bus message and the address of the device it is sent to.
+    pub fn to_message(&self) -> (u8, Message) {
+        match *self {
+            Command::SetOutput {
+                addr,
+                output,
+                state,
+            } => (addr, Message::SetOutput { output, state }),
+            Command::TriggerInput {
+                addr,
+                input,
+                trigger,
+            } => (addr, Message::TriggerInput { input, trigger }),
+            Command::CallProcedure { addr, proc_id } => (addr, Message::CallProcedure { proc_id }),
+            Command::Shutter { addr, shutter, cmd } => (
+                addr,
+                Message::ShutterCmd {
+                    shutter_idx: shutter,
+                    cmd,
+                },
+            ),
+            Command::RequestStatus { addr } => (addr, Message::RequestStatus),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shutters::{Cmd, TargetPosition};
+
+    fn parse(json: &str) -> Result<Command, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn commands() {
+        let command =
+            parse(r#"{"command": "set_output", "addr": 1, "output": 3, "state": "toggle"}"#);
+        assert!(matches!(
+            command.unwrap(),
+            Command::SetOutput {
+                addr: 1,
+                output: 3,
+                state: OutputChangeRequest::Toggle
+            }
+        ));
+
+        let command = parse(
+            r#"{"command": "trigger_input", "addr": 2, "input": 4, "trigger": "long_click"}"#,
+        );
+        assert!(matches!(
+            command.unwrap(),
+            Command::TriggerInput {
+                addr: 2,
+                input: 4,
+                trigger: Trigger::LongClick
+            }
+        ));
+
+        let command = parse(r#"{"command": "call_procedure", "addr": 3, "proc_id": 7}"#);
+        assert!(matches!(
+            command.unwrap(),
+            Command::CallProcedure {
+                addr: 3,
+                proc_id: 7
+            }
+        ));
+
+        // Broadcast reaches all devices.
+        let command = parse(r#"{"command": "request_status", "addr": 63}"#);
+        assert!(matches!(
+            command.unwrap(),
+            Command::RequestStatus { addr: 63 }
+        ));
+    }
+
+    #[test]
+    fn shutter_commands() {
+        // Driver command of shutter 1 of device 5, given as JSON.
+        let cmd = |cmd: &str| {
+            let json = format!(
+                r#"{{"command": "shutter", "addr": 5, "shutter": 1, "cmd": {}}}"#,
+                cmd
+            );
+            match parse(&json) {
+                Ok(Command::Shutter {
+                    addr: 5,
+                    shutter: 1,
+                    cmd,
+                }) => Ok(cmd),
+                Ok(command) => panic!("Unexpected {:?}", command),
+                Err(err) => Err(err),
+            }
+        };
+
+        assert_eq!(cmd(r#""open""#).unwrap(), Cmd::Open);
+        assert_eq!(cmd(r#"{"tilt": 100}"#).unwrap(), Cmd::Tilt(100));
+        assert_eq!(
+            cmd(r#"{"go": {"height": 30, "tilt": 0}}"#).unwrap(),
+            Cmd::Go(TargetPosition::new(30, 0))
+        );
+        assert_eq!(cmd(r#"{"set_io": [1, 2]}"#).unwrap(), Cmd::SetIO(1, 2));
+
+        assert!(cmd(r#"{"tilt": 101}"#).is_err());
+        assert!(cmd(r#"{"go": {"height": 250, "tilt": 0}}"#).is_err());
+        assert!(cmd(r#"{"go": {"height": 0, "tilt": 101}}"#).is_err());
+        assert!(cmd(r#""fly""#).is_err());
+    }
+
+    #[test]
+    fn address_out_of_range() {
+        let err = parse(r#"{"command": "request_status", "addr": 64}"#).unwrap_err();
+        assert!(err.to_string().contains("out of range"), "{}", err);
+        assert!(parse(r#"{"command": "call_procedure", "addr": 127, "proc_id": 1}"#).is_err());
+        assert!(parse(r#"{"command": "call_procedure", "addr": 256, "proc_id": 1}"#).is_err());
+        assert!(
+            parse(r#"{"command": "shutter", "addr": 100, "shutter": 1, "cmd": "open"}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn shutter_message() {
+        let command = parse(r#"{"command": "shutter", "addr": 5, "shutter": 2, "cmd": "close"}"#);
+        let (addr, message) = command.unwrap().to_message();
+        assert_eq!(addr, 5);
+        assert!(matches!(
+            message,
+            Message::ShutterCmd {
+                shutter_idx: 2,
+                cmd: Cmd::Close
+            }
+        ));
+    }
+}
@@ -1,4 +1,5 @@
 use crate::message::MessageRaw;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::sync::mpsc;
@@ -18,11 +19,58 @@ pub struct Comm {
     pub rx: mpsc::Receiver<MessageRaw>,
     pub reader: JoinHandle<anyhow::Result<()>>,
     pub writer: JoinHandle<anyhow::Result<()>>,
+    pub stats: Arc<Stats>,
+    pub queues: Queues,
+}
+
+/// Bridge traffic counters, reported as gate diagnostics.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// CAN frames read from the bridge.
+    pub received: AtomicU64,
+    /// CAN frames written to the bridge.
+    pub sent: AtomicU64,
+    /// Chunks skipped by synchronization and frames that failed to parse.
+    pub errors: AtomicU64,
+    /// Unix time of the last time broadcast; 0 if none yet.
+    pub last_time_broadcast: AtomicI64,
+}
+
+impl Stats {
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Handles measuring the bridge queues without keeping them open.
+#[derive(Debug, Clone)]
+pub struct Queues {
+    rx: mpsc::WeakSender<MessageRaw>,
+    tx: mpsc::WeakSender<MessageRaw>,
+}
+
+impl Queues {
+    fn depth(queue: &mpsc::WeakSender<MessageRaw>) -> usize {
+        queue
+            .upgrade()
+            .map_or(0, |queue| queue.max_capacity() - queue.capacity())
+    }
+
+    /// Frames waiting to be handled.
+    pub fn rx_depth(&self) -> usize {
+        Self::depth(&self.rx)
+    }
+
+    /// Frames waiting to be written to the bridge.
+    pub fn tx_depth(&self) -> usize {
+        Self::depth(&self.tx)
+    }
 }
 
 async fn reader(
     mut port: ReadHalf<tokio_serial::SerialStream>,
     channel: mpsc::Sender<MessageRaw>,
+    stats: Arc<Stats>,
 ) -> anyhow::Result<()> {
     let mut buf = [0u8; 512];
     loop {
@@ -43,6 +91,7 @@ async fn reader(
                 "Synchronization 1 failed - preambule error. Skipping chunk: {:?}",
                 &buf[0..read_len]
             );
+            stats.error();
             continue;
         }
 
@@ -61,6 +110,7 @@ async fn reader(
                     "Synchronization 2 failed - preambule error. Skipping chunk: {:?}",
                     &buf[0..read_len]
                 );
+                stats.error();
                 continue;
             }
         };
@@ -72,6 +122,7 @@ async fn reader(
                 read_len,
                 &buf[0..read_len]
             );
+            stats.error();
             continue;
         }
         let packet = &buf[PREAMBULE_LENGTH..PREAMBULE_LENGTH + body_len];
@@ -86,12 +137,14 @@ async fn reader(
                 prefix = 3,
                 "Invalid length or packet too short"
             );
+            stats.error();
             continue;
         }
         let data = &packet[3..3 + length];
         debug!("USB->RX: {} bytes: {:02x?}", body_len, packet);
 
         let raw = MessageRaw::from_bytes(addr, msg_type, data);
+        stats.received.fetch_add(1, Ordering::Relaxed);
         channel.send(raw).await?;
     }
 }
@@ -99,6 +152,7 @@ async fn reader(
 async fn writer(
     mut port: WriteHalf<tokio_serial::SerialStream>,
     mut channel: mpsc::Receiver<MessageRaw>,
+    stats: Arc<Stats>,
 ) -> anyhow::Result<()> {
     loop {
         let mut buf = [0u8; 64];
@@ -119,6 +173,7 @@ async fn writer(
             match port.write(msg_buf).await {
                 Ok(size) => {
                     debug!("TX->USB: {} bytes: {:02x?}", size, msg_buf);
+                    stats.sent.fetch_add(1, Ordering::Relaxed);
                 }
                 Err(err) => {
                     anyhow::bail!("Error while sending to port {:?}", err);
@@ -139,8 +194,13 @@ pub async fn run(port_name: String, baud_rate: u32) -> anyhow::Result<Comm> {
 
     let (out_tx, out_rx) = mpsc::channel(15);
     let (in_tx, in_rx) = mpsc::channel(15);
-    let reader = reader(port_read, in_tx);
-    let writer = writer(port_write, out_rx);
+    let stats = Arc::new(Stats::default());
+    let queues = Queues {
+        rx: in_tx.downgrade(),
+        tx: out_tx.downgrade(),
+    };
+    let reader = reader(port_read, in_tx, stats.clone());
+    let writer = writer(port_write, out_rx, stats.clone());
 
     let reader_handle = tokio::spawn(reader);
     let writer_handle = tokio::spawn(writer);
@@ -150,6 +210,8 @@ pub async fn run(port_name: String, baud_rate: u32) -> anyhow::Result<Comm> {
         rx: in_rx,
         writer: writer_handle,
         reader: reader_handle,
+        stats,
+        queues,
     })
     // tokio::try_join!(reader, writer)?;
     //Ok((out_tx, in_rx))
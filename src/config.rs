@@ -1,30 +1,446 @@
+use crate::consts::{self, OutIdx, ProcIdx, ShutterIdx};
+use crate::homeassistant::discovery::{self, OutputKind};
+use crate::homeassistant::{Broker, Topics};
+use crate::message::BROADCAST_ADDRESS;
+use crate::shutters::{self, Cmd, Timings};
+use anyhow::Context;
+use serde::de::{Deserializer, MapAccess, Visitor};
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
 use std::path::Path;
+use std::str::FromStr;
 
-#[derive(Debug, Deserialize)]
+/// Default time shutters are driven after reaching an end stop, in seconds.
+pub const DEFAULT_OVER_TIME: f32 = 2.0;
+
+#[derive(Debug, Default, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct IOConfig {
     pub id: u8,
     #[serde(rename = "type")]
     pub io_type: Option<String>,
+    /// Friendly name shown in HA. Defaults to the label.
+    pub name: Option<String>,
+    /// Material design icon, like `mdi:ceiling-light`.
+    pub icon: Option<String>,
+    /// `config` or `diagnostic` to hide the entity from default dashboards.
+    pub entity_category: Option<String>,
+    /// Explicit HA unique ID, kept when the IO is rewired. Derived from the
+    /// device address and IO index by default.
+    pub unique_id: Option<String>,
+    /// Where the IO was connected before rewiring. Unique IDs are derived
+    /// from the old location, so HA keeps the entity and its history.
+    pub migrated_from: Option<IOLocation>,
+}
+
+/// Device address and IO index.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IOLocation {
+    /// Device address, the same device by default.
+    pub addr: Option<u8>,
+    pub id: u8,
+}
+
+/// Consecutive IO channels labeled in order, instead of listing each one:
+///
+/// ```yaml
+/// outputs:
+///   count: 8
+///   labels: [kitchen, hall, ~, office]
+/// ```
+///
+/// Channels without a label are skipped, unless `template` gives them one.
+/// Can be mixed with labels listed as usual.
+#[derive(Debug)]
+struct IORange {
+    count: Option<u8>,
+    /// Index of the first channel.
+    first: u8,
+    labels: Vec<Option<String>>,
+    /// Label of channels not in `labels`, `{}` is replaced with the index.
+    template: Option<String>,
+    /// Type of all channels.
+    io_type: Option<String>,
+}
+
+impl IORange {
+    fn expand(self, ios: &mut HashMap<String, IOConfig>) -> Result<(), String> {
+        let count = self.count.ok_or("IO range requires count")?;
+        if self.labels.len() > count as usize {
+            return Err(format!(
+                "{} labels given for {} channels",
+                self.labels.len(),
+                count
+            ));
+        }
+        for offset in 0..count {
+            let id = self
+                .first
+                .checked_add(offset)
+                .ok_or_else(|| format!("Channel index out of range after {}", self.first))?;
+            let label = match self.labels.get(offset as usize).cloned().flatten() {
+                Some(label) => label,
+                None => match &self.template {
+                    Some(template) => template.replace("{}", &id.to_string()),
+                    None => continue,
+                },
+            };
+            let io = IOConfig {
+                id,
+                io_type: self.io_type.clone(),
+                ..Default::default()
+            };
+            if ios.insert(label.clone(), io).is_some() {
+                return Err(format!("Duplicated label {}", label));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// IOs by label, given as a map and/or a range. Range keys can't be used as
+/// labels.
+fn deserialize_ios<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, IOConfig>, D::Error> {
+    struct IOVisitor;
+
+    impl<'de> Visitor<'de> for IOVisitor {
+        type Value = HashMap<String, IOConfig>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a map of labels or an IO range")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut ios = HashMap::new();
+            let mut range = IORange {
+                count: None,
+                first: 1,
+                labels: Vec::new(),
+                template: None,
+                io_type: None,
+            };
+            let mut is_range = false;
+            while let Some(key) = map.next_key::<String>()? {
+                match key.as_str() {
+                    "count" => range.count = Some(map.next_value()?),
+                    "first" => range.first = map.next_value()?,
+                    "labels" => range.labels = map.next_value()?,
+                    "template" => range.template = Some(map.next_value()?),
+                    "type" => range.io_type = Some(map.next_value()?),
+                    _ => {
+                        let io = map.next_value()?;
+                        if ios.insert(key.clone(), io).is_some() {
+                            return Err(serde::de::Error::custom(format!(
+                                "Duplicated label {}",
+                                key
+                            )));
+                        }
+                        continue;
+                    }
+                }
+                is_range = true;
+            }
+            if is_range {
+                range.expand(&mut ios).map_err(serde::de::Error::custom)?;
+            }
+            Ok(ios)
+        }
+    }
+
+    deserializer.deserialize_map(IOVisitor)
+}
+
+impl IOConfig {
+    /// Name shown in HA for the IO with a given label.
+    pub fn display_name<'a>(&'a self, label: &'a str) -> &'a str {
+        self.name.as_deref().unwrap_or(label)
+    }
+}
+
+/// Shutter driven by a pair of outputs on the device.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShutterConfig {
+    /// Shutter index in the device firmware.
+    pub id: ShutterIdx,
+    /// Output that drives the shutter down.
+    pub down: OutIdx,
+    /// Output that drives the shutter up.
+    pub up: OutIdx,
+    /// Full travel from closed to open, in seconds.
+    pub rise_time: Option<f32>,
+    /// Full travel from open to closed, in seconds.
+    pub drop_time: Option<f32>,
+    /// Full tilt from open to closed, in seconds.
+    pub tilt_time: Option<f32>,
+    /// Additional drive time at the end stops, in seconds.
+    pub over_time: Option<f32>,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct DeviceConfig {
     pub addr: u8,
+    /// Friendly device name shown in HA. Defaults to the config key.
+    pub name: Option<String>,
+    /// Area (room) HA assigns the device to on first discovery. HA has no
+    /// per-entity area in MQTT discovery.
+    #[serde(alias = "suggested_area")]
+    pub area: Option<String>,
+    /// Address of the board this one replaced. Unique IDs keep using it.
+    pub migrated_from: Option<u8>,
+    /// Outputs by label, listed or given as a range.
+    #[serde(deserialize_with = "deserialize_ios")]
     pub outputs: HashMap<String, IOConfig>,
+    #[serde(deserialize_with = "deserialize_ios")]
     pub inputs: HashMap<String, IOConfig>,
+    #[serde(default)]
+    pub shutters: HashMap<String, ShutterConfig>,
+    /// Procedures programmed in the device VM, callable from HA.
+    #[serde(default)]
+    pub procedures: HashMap<String, ProcIdx>,
 }
 
-#[derive(Debug, Deserialize)]
+impl ShutterConfig {
+    /// Configured travel times, defaults for the missing ones.
+    pub fn timings(&self) -> Timings {
+        let default = Timings::default();
+        Timings {
+            rise: self.rise_time.unwrap_or(default.rise),
+            drop: self.drop_time.unwrap_or(default.drop),
+            tilt: self.tilt_time.unwrap_or(default.tilt),
+        }
+    }
+
+    /// Commands configuring the firmware shutter driver.
+    pub fn commands(&self) -> Vec<Cmd> {
+        let mut commands = vec![Cmd::SetIO(self.down, self.up)];
+        // Keep firmware defaults unless configured.
+        if self.rise_time.is_some() || self.drop_time.is_some() || self.tilt_time.is_some() {
+            commands.extend(self.timings().commands(self.over_time()));
+        }
+        commands
+    }
+
+    pub fn over_time(&self) -> f32 {
+        self.over_time.unwrap_or(DEFAULT_OVER_TIME)
+    }
+}
+
+impl DeviceConfig {
+    /// Do entities of the device keep unique IDs from a previous wiring?
+    pub fn is_migrated(&self) -> bool {
+        self.migrated_from.is_some()
+            || self
+                .outputs
+                .values()
+                .chain(self.inputs.values())
+                .any(|io| io.migrated_from.is_some() || io.unique_id.is_some())
+    }
+
+    /// Device address unique IDs of the device entities are derived from.
+    pub fn id_addr(&self) -> u8 {
+        self.migrated_from.unwrap_or(self.addr)
+    }
+
+    /// Device address and IO index the unique IDs of an IO are derived from.
+    pub fn id_location(&self, io: &IOConfig) -> (u8, u8) {
+        let addr = self.id_addr();
+        match io.migrated_from {
+            Some(old) => (old.addr.unwrap_or(addr), old.id),
+            None => (addr, io.id),
+        }
+    }
+
+    /// Is the output driven by a shutter (and not controlled directly)?
+    pub fn is_shutter_output(&self, output: OutIdx) -> bool {
+        self.shutters
+            .values()
+            .any(|shutter| shutter.down == output || shutter.up == output)
+    }
+}
+
+/// Secret value, hidden when logged.
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+impl FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Secret(s.to_string()))
+    }
+}
+
+/// Daemon settings from the `gate:` section. Command line options and
+/// `IO_GATE_*` environment variables take precedence. Read on startup only.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GateConfig {
+    pub port_name: Option<String>,
+    pub baud_rate: Option<u32>,
+    pub mqtt_host: Option<String>,
+    pub mqtt_port: Option<u16>,
+    pub mqtt_username: Option<String>,
+    pub mqtt_password: Option<Secret>,
+    /// File containing the password, preferred over keeping it in config.
+    pub mqtt_password_file: Option<String>,
+    pub mqtt_tls: Option<bool>,
+    pub mqtt_ca_file: Option<String>,
+    pub mqtt_client_cert: Option<String>,
+    pub mqtt_client_key: Option<String>,
+    pub mqtt_websocket: Option<bool>,
+    pub mqtt_ws_path: Option<String>,
+    pub discovery_prefix: Option<String>,
+    pub control_prefix: Option<String>,
+    pub node_id: Option<String>,
+    pub status_interval: Option<u64>,
+    pub reload_interval: Option<u64>,
+    pub json_api: Option<bool>,
+    pub raw_bus: Option<bool>,
+}
+
+/// Daemon settings merged from the command line, environment, the config
+/// file and defaults.
+#[derive(Debug)]
+pub struct Settings {
+    pub port_name: String,
+    pub baud_rate: u32,
+    pub broker: Broker,
+    pub discovery_prefix: String,
+    pub control_prefix: String,
+    pub node_id: String,
+    pub status_interval: u64,
+    pub reload_interval: u64,
+    pub json_api: bool,
+    pub raw_bus: bool,
+}
+
+impl Settings {
+    /// Settings given on the command line (`cli`) take precedence over the
+    /// `gate` section.
+    pub fn resolve(cli: &GateConfig, gate: &GateConfig) -> anyhow::Result<Self> {
+        let password = match (&cli.mqtt_password, &cli.mqtt_password_file) {
+            (Some(password), _) => Some(password.clone()),
+            (None, Some(file)) => Some(read_secret(file)?),
+            (None, None) => match (&gate.mqtt_password, &gate.mqtt_password_file) {
+                (Some(password), _) => Some(password.clone()),
+                (None, Some(file)) => Some(read_secret(file)?),
+                (None, None) => None,
+            },
+        };
+        let text =
+            |cli: &Option<String>, gate: &Option<String>| cli.clone().or_else(|| gate.clone());
+        let broker = Broker {
+            // Checked when connecting, other commands don't need it.
+            host: text(&cli.mqtt_host, &gate.mqtt_host).unwrap_or_default(),
+            port: cli.mqtt_port.or(gate.mqtt_port).unwrap_or(1883),
+            username: text(&cli.mqtt_username, &gate.mqtt_username).unwrap_or_default(),
+            password: password.unwrap_or_default(),
+            tls: cli.mqtt_tls.or(gate.mqtt_tls).unwrap_or(false),
+            ca_file: text(&cli.mqtt_ca_file, &gate.mqtt_ca_file),
+            client_cert: text(&cli.mqtt_client_cert, &gate.mqtt_client_cert),
+            client_key: text(&cli.mqtt_client_key, &gate.mqtt_client_key),
+            websocket: cli.mqtt_websocket.or(gate.mqtt_websocket).unwrap_or(false),
+            ws_path: text(&cli.mqtt_ws_path, &gate.mqtt_ws_path)
+                .unwrap_or_else(|| "/mqtt".to_string()),
+        };
+        if !broker.tls && (broker.ca_file.is_some() || broker.client_cert.is_some()) {
+            anyhow::bail!("MQTT certificates require TLS to be enabled");
+        }
+        if broker.client_cert.is_some() != broker.client_key.is_some() {
+            anyhow::bail!("MQTT client certificate and key must be given together");
+        }
+        if broker.client_cert.is_some() && broker.ca_file.is_none() {
+            anyhow::bail!("MQTT client certificate requires a CA file");
+        }
+
+        Ok(Self {
+            port_name: text(&cli.port_name, &gate.port_name)
+                .unwrap_or_else(|| "/dev/ttyACM0".to_string()),
+            baud_rate: cli.baud_rate.or(gate.baud_rate).unwrap_or(115200),
+            broker,
+            discovery_prefix: text(&cli.discovery_prefix, &gate.discovery_prefix)
+                .unwrap_or_else(|| consts::HA_DISCOVERY_TOPIC.to_string()),
+            control_prefix: text(&cli.control_prefix, &gate.control_prefix)
+                .unwrap_or_else(|| consts::HA_CONTROL_TOPIC.to_string()),
+            node_id: text(&cli.node_id, &gate.node_id)
+                .unwrap_or_else(|| consts::GATE_NAME.to_string()),
+            status_interval: cli.status_interval.or(gate.status_interval).unwrap_or(0),
+            reload_interval: cli.reload_interval.or(gate.reload_interval).unwrap_or(5),
+            json_api: cli.json_api.or(gate.json_api).unwrap_or(false),
+            raw_bus: cli.raw_bus.or(gate.raw_bus).unwrap_or(false),
+        })
+    }
+}
+
+/// Read a secret from a file, without the trailing newline.
+fn read_secret(path: &str) -> anyhow::Result<Secret> {
+    let secret = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read secret file {}", path))?;
+    Ok(Secret(secret.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// Config file: the `gate` section and devices under any other keys.
+#[derive(Debug, Default)]
 pub struct Config {
-    #[serde(flatten)]
+    pub gate: GateConfig,
     pub devices: HashMap<String, DeviceConfig>,
 }
 
+impl<'de> Deserialize<'de> for Config {
+    // Not derived with a flattened map, which loses positions in errors.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ConfigVisitor;
+
+        impl<'de> Visitor<'de> for ConfigVisitor {
+            type Value = Config;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a map of devices and the gate section")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Config, A::Error> {
+                let mut config = Config {
+                    gate: GateConfig::default(),
+                    devices: HashMap::new(),
+                };
+                while let Some(key) = map.next_key::<String>()? {
+                    if key == GATE_SECTION {
+                        config.gate = map.next_value()?;
+                    } else {
+                        let device = map.next_value()?;
+                        if config.devices.insert(key.clone(), device).is_some() {
+                            return Err(serde::de::Error::custom(format!(
+                                "duplicate device {}",
+                                key
+                            )));
+                        }
+                    }
+                }
+                Ok(config)
+            }
+        }
+
+        deserializer.deserialize_map(ConfigVisitor)
+    }
+}
+
+/// Config key of the daemon settings.
+pub const GATE_SECTION: &str = "gate";
+
 impl Config {
     pub fn from_file<P: AsRef<Path>>(filename: P) -> anyhow::Result<Self> {
         let handle = File::open(filename)?;
@@ -32,4 +448,818 @@ impl Config {
 
         Ok(data)
     }
+
+    /// Read the config and fail listing all problems found by `check`.
+    pub fn from_file_checked<P: AsRef<Path>>(filename: P) -> anyhow::Result<Self> {
+        let filename = filename.as_ref();
+        let source = std::fs::read_to_string(filename)?;
+        let data: Config = serde_yaml::from_str(&source)?;
+
+        let problems = data.check(&source);
+        if problems.is_empty() {
+            return Ok(data);
+        }
+        let mut report = format!("{} problem(s) in {}:", problems.len(), filename.display());
+        for problem in problems {
+            match problem.line {
+                Some(line) => report.push_str(&format!("\n{}:{}: ", filename.display(), line)),
+                None => report.push_str(&format!("\n{}: ", filename.display())),
+            }
+            report.push_str(&problem.message);
+        }
+        anyhow::bail!(report)
+    }
+
+    /// Validate the config parsed from `source`. The source is used only to
+    /// find line numbers.
+    pub fn check(&self, source: &str) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let mut report = |path: &[&str], message: String| {
+            problems.push(Problem {
+                line: key_line(source, path),
+                message,
+            });
+        };
+
+        // Conflicting on the command line too.
+        if self.gate.mqtt_password.is_some() && self.gate.mqtt_password_file.is_some() {
+            report(
+                &[GATE_SECTION, "mqtt_password_file"],
+                "mqtt_password and mqtt_password_file can't be used together".to_string(),
+            );
+        }
+
+        // Stable order for reporting duplicates.
+        let mut devices: Vec<_> = self.devices.iter().collect();
+        devices.sort_by_key(|(name, _)| key_line(source, &[name.as_str()]));
+
+        // Derived unique IDs include the node ID, which can also be given on
+        // the command line. Collisions don't depend on it, except with
+        // explicit unique IDs.
+        let node_id = self.gate.node_id.as_deref().unwrap_or(consts::GATE_NAME);
+        let topics = Topics::new("", "", node_id);
+
+        let mut addresses: HashMap<u8, &str> = HashMap::new();
+        let mut unique_ids: HashMap<String, (&str, &str)> = HashMap::new();
+        for (name, device) in devices {
+            let name = name.as_str();
+            if device.addr == BROADCAST_ADDRESS {
+                report(
+                    &[name, "addr"],
+                    format!(
+                        "Device {} uses the broadcast address {:#x}",
+                        name, device.addr
+                    ),
+                );
+            } else if device.addr > MAX_ADDRESS {
+                report(
+                    &[name, "addr"],
+                    format!(
+                        "Device {} address {:#x} is out of range (0-{:#x})",
+                        name, device.addr, MAX_ADDRESS
+                    ),
+                );
+            } else if let Some(other) = addresses.insert(device.addr, name) {
+                report(
+                    &[name, "addr"],
+                    format!(
+                        "Device {} address {:#x} is already used by {}",
+                        name, device.addr, other
+                    ),
+                );
+            }
+
+            let mut labels: HashSet<&str> = HashSet::new();
+            for (section, ios) in [("outputs", &device.outputs), ("inputs", &device.inputs)] {
+                let mut ids: HashMap<u8, &str> = HashMap::new();
+                for (label, io) in sorted(source, &[name, section], ios) {
+                    let path = [name, section, label];
+                    if !labels.insert(label) {
+                        report(
+                            &path,
+                            format!("Duplicated label {} in device {}", label, name),
+                        );
+                    }
+                    if io.id == 0 {
+                        report(
+                            &[name, section, label, "id"],
+                            format!("IO index 0 of {} is reserved", label),
+                        );
+                    } else if let Some(other) = ids.insert(io.id, label) {
+                        report(
+                            &[name, section, label, "id"],
+                            format!("Index {} of {} is already used by {}", io.id, label, other),
+                        );
+                    }
+                    if section == "outputs"
+                        && OutputKind::from_config(io.io_type.as_deref()).is_none()
+                    {
+                        report(
+                            &[name, section, label, "type"],
+                            format!(
+                                "Unknown output type {:?} of {}",
+                                io.io_type.as_deref().unwrap_or_default(),
+                                label
+                            ),
+                        );
+                    }
+                    let input_class = io.io_type.as_deref().filter(|_| section == "inputs");
+                    if let Some(class) = input_class {
+                        if !discovery::INPUT_CLASSES.contains(&class) {
+                            report(
+                                &[name, section, label, "type"],
+                                format!("Unknown input type {:?} of {}", class, label),
+                            );
+                        }
+                    }
+                    if let Some(category) = io.entity_category.as_deref() {
+                        if !["config", "diagnostic"].contains(&category) {
+                            report(
+                                &[name, section, label, "entity_category"],
+                                format!("Unknown entity category {:?} of {}", category, label),
+                            );
+                        }
+                    }
+                }
+            }
+
+            let mut ids: HashMap<u8, &str> = HashMap::new();
+            for (label, shutter) in sorted(source, &[name, "shutters"], &device.shutters) {
+                if !labels.insert(label) {
+                    report(
+                        &[name, "shutters", label],
+                        format!("Duplicated label {} in device {}", label, name),
+                    );
+                }
+                if let Some(other) = ids.insert(shutter.id, label) {
+                    report(
+                        &[name, "shutters", label, "id"],
+                        format!(
+                            "Shutter index {} of {} is already used by {}",
+                            shutter.id, label, other
+                        ),
+                    );
+                }
+                for (key, output) in [("down", shutter.down), ("up", shutter.up)] {
+                    if output == 0 {
+                        report(
+                            &[name, "shutters", label, key],
+                            format!("IO index 0 of shutter {} is reserved", label),
+                        );
+                    }
+                }
+                if shutter.down == shutter.up {
+                    report(
+                        &[name, "shutters", label, "up"],
+                        format!(
+                            "Shutter {} uses output {} both down and up",
+                            label, shutter.up
+                        ),
+                    );
+                }
+                let times = [
+                    ("rise_time", shutter.rise_time),
+                    ("drop_time", shutter.drop_time),
+                    ("tilt_time", shutter.tilt_time),
+                    ("over_time", shutter.over_time),
+                ];
+                for (key, time) in times {
+                    let Some(time) = time else {
+                        continue;
+                    };
+                    if time.is_nan() || time <= 0.0 {
+                        report(
+                            &[name, "shutters", label, key],
+                            format!(
+                                "{} of shutter {} must be positive, not {}",
+                                key, label, time
+                            ),
+                        );
+                    } else if time > shutters::MAX_TIME {
+                        report(
+                            &[name, "shutters", label, key],
+                            format!(
+                                "{} of shutter {} is over the longest {}s",
+                                key,
+                                label,
+                                shutters::MAX_TIME
+                            ),
+                        );
+                    }
+                }
+            }
+
+            let mut ids: HashMap<u8, &str> = HashMap::new();
+            for (label, proc_id) in sorted(source, &[name, "procedures"], &device.procedures) {
+                if !labels.insert(label) {
+                    report(
+                        &[name, "procedures", label],
+                        format!("Duplicated label {} in device {}", label, name),
+                    );
+                }
+                if let Some(other) = ids.insert(*proc_id, label) {
+                    report(
+                        &[name, "procedures", label],
+                        format!(
+                            "Procedure {} of {} is already used by {}",
+                            proc_id, label, other
+                        ),
+                    );
+                }
+            }
+
+            // Unique IDs as announced, migrated and explicit ones included.
+            let path = |section, label| match section {
+                "" => vec![name],
+                _ => vec![name, section, label, "unique_id"],
+            };
+            let mut entities = discovery::unique_ids(&topics, device);
+            entities.sort_by_key(|(_, section, label)| key_line(source, &path(section, label)));
+            let mut reported = HashSet::new();
+            for (unique_id, section, label) in entities {
+                let Some(&(device, other)) = unique_ids.get(&unique_id) else {
+                    unique_ids.insert(unique_id, (name, label));
+                    continue;
+                };
+                if section.is_empty() {
+                    // Status sensors collide all at once.
+                    if reported.insert((section, "")) {
+                        report(
+                            &[name],
+                            format!(
+                                "Unique IDs of status sensors of {} are already used by device {}",
+                                name, device
+                            ),
+                        );
+                    }
+                } else if reported.insert((section, label)) {
+                    report(
+                        &path(section, label),
+                        format!(
+                            "Unique ID {} of {} is already used by {} in device {}",
+                            unique_id, label, other, device
+                        ),
+                    );
+                }
+            }
+        }
+
+        problems.sort_by_key(|problem| problem.line);
+        problems
+    }
+
+    /// Find a shutter by device address and shutter index.
+    pub fn shutter(&self, addr: u8, shutter: ShutterIdx) -> Option<&ShutterConfig> {
+        self.devices
+            .values()
+            .filter(|device| device.addr == addr)
+            .flat_map(|device| device.shutters.values())
+            .find(|cfg| cfg.id == shutter)
+    }
+}
+
+/// Highest device address; 6 bits, the last one is the broadcast.
+pub const MAX_ADDRESS: u8 = BROADCAST_ADDRESS - 1;
+
+/// Problem found by `Config::check`.
+#[derive(Debug)]
+pub struct Problem {
+    /// Line in the config file, when found.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Map entries ordered as in the config file.
+fn sorted<'a, T>(
+    source: &str,
+    path: &[&str],
+    map: &'a HashMap<String, T>,
+) -> Vec<(&'a str, &'a T)> {
+    let mut entries: Vec<_> = map
+        .iter()
+        .map(|(key, value)| (key.as_str(), value))
+        .collect();
+    entries.sort_by_key(|(key, _)| {
+        let mut path = path.to_vec();
+        path.push(key);
+        key_line(source, &path)
+    });
+    entries
+}
+
+/// Lines (from 0) and indentation of the keys along a nested mapping path,
+/// found by indentation. Stops at the deepest key found, like a mapping
+/// written in the flow style.
+fn key_path(lines: &[&str], path: &[&str]) -> Vec<(usize, usize)> {
+    let mut found = Vec::new();
+    // Lines of the parent block and its indentation.
+    let mut start = 0;
+    let mut parent_indent: Option<usize> = None;
+
+    for key in path {
+        let mut child_indent = None;
+        let mut key_at = None;
+        for (no, line) in lines.iter().enumerate().skip(start) {
+            let trimmed = line.trim_start();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let indent = line.len() - trimmed.len();
+            if parent_indent.is_some_and(|parent| indent <= parent) {
+                // End of the parent block.
+                break;
+            }
+            if *child_indent.get_or_insert(indent) != indent {
+                continue;
+            }
+            if is_key(trimmed, key) {
+                key_at = Some((no, indent));
+                break;
+            }
+        }
+        let Some((no, indent)) = key_at else {
+            break;
+        };
+        found.push((no, indent));
+        start = no + 1;
+        parent_indent = Some(indent);
+    }
+    found
+}
+
+/// Line (from 1) of a nested mapping key, see `key_path`.
+fn key_line(source: &str, path: &[&str]) -> Option<usize> {
+    let lines: Vec<&str> = source.lines().collect();
+    key_path(&lines, path).last().map(|(no, _)| no + 1)
+}
+
+/// Does the (trimmed) line start with the mapping key? The key can be quoted.
+fn is_key(line: &str, key: &str) -> bool {
+    let quoted = ['"', '\''].into_iter().find_map(|quote| {
+        line.strip_prefix(quote)?
+            .strip_prefix(key)?
+            .strip_prefix(quote)
+    });
+    quoted
+        .or_else(|| line.strip_prefix(key))
+        .is_some_and(|rest| rest.trim_start().starts_with(':'))
+}
+
+/// Set values in a block mapping of the YAML source. Existing keys are
+/// replaced in place, missing ones are added at the end of the block. The
+/// rest of the source is kept as is.
+fn set_values(source: &str, path: &[&str], values: &[(&str, String)]) -> anyhow::Result<String> {
+    let lines: Vec<&str> = source.lines().collect();
+    let found = key_path(&lines, path);
+    let (at, indent) = match found.last() {
+        Some(&last) if found.len() == path.len() => last,
+        _ => anyhow::bail!("{} not found", path.join(".")),
+    };
+    let inline = lines[at]
+        .split_once(':')
+        .map_or("", |(_, value)| value.trim());
+    if !inline.is_empty() && !inline.starts_with('#') {
+        anyhow::bail!("{} is not a block mapping, edit it by hand", path.join("."));
+    }
+
+    // Block of the mapping, without trailing comments and blank lines.
+    let mut end = at + 1;
+    let mut child_indent = None;
+    for (no, line) in lines.iter().enumerate().skip(at + 1) {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let line_indent = line.len() - trimmed.len();
+        if line_indent <= indent {
+            break;
+        }
+        child_indent.get_or_insert(line_indent);
+        end = no + 1;
+    }
+    let child_indent = child_indent.unwrap_or(indent + 2);
+
+    let mut edited: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+    let mut added = Vec::new();
+    for (key, value) in values {
+        let entry = format!("{}{}: {}", " ".repeat(child_indent), key, value);
+        let existing = (at + 1..end).find(|no| {
+            let line = lines[*no];
+            let trimmed = line.trim_start();
+            line.len() - trimmed.len() == child_indent && is_key(trimmed, key)
+        });
+        match existing {
+            Some(no) => {
+                // Keep a trailing comment.
+                let comment = lines[no].find(" #").map_or("", |pos| &lines[no][pos..]);
+                edited[no] = entry + comment;
+            }
+            None => added.push(entry),
+        }
+    }
+    edited.splice(end..end, added);
+
+    let mut result = edited.join("\n");
+    if source.ends_with('\n') {
+        result.push('\n');
+    }
+    Ok(result)
+}
+
+/// Store calibrated shutter timings in the config file. Only the timings of
+/// the shutter are changed, comments and formatting are kept. The original
+/// file is kept with a `.bak` suffix.
+pub fn store_shutter_timings<P: AsRef<Path>>(
+    filename: P,
+    addr: u8,
+    shutter: ShutterIdx,
+    timings: &Timings,
+) -> anyhow::Result<()> {
+    let filename = filename.as_ref();
+    let source = std::fs::read_to_string(filename)?;
+    let config: Config = serde_yaml::from_str(&source)?;
+    let (device, label) = config
+        .devices
+        .iter()
+        .filter(|(_, device)| device.addr == addr)
+        .flat_map(|(name, device)| device.shutters.iter().map(move |shutter| (name, shutter)))
+        .find(|(_, (_, cfg))| cfg.id == shutter)
+        .map(|(name, (label, _))| (name.as_str(), label.as_str()))
+        .ok_or_else(|| anyhow::anyhow!("Shutter {} of device {} not in config", shutter, addr))?;
+
+    // Centisecond precision is plenty.
+    let round = |seconds: f32| (seconds * 100.0).round() / 100.0;
+    let stored = [
+        round(timings.rise),
+        round(timings.drop),
+        round(timings.tilt),
+    ];
+    let values = [
+        ("rise_time", stored[0].to_string()),
+        ("drop_time", stored[1].to_string()),
+        ("tilt_time", stored[2].to_string()),
+    ];
+    let edited = set_values(&source, &[device, "shutters", label], &values)?;
+
+    // Don't break the config with a bad edit.
+    let check: Config = serde_yaml::from_str(&edited)?;
+    let shutter_config = check.shutter(addr, shutter);
+    let found = shutter_config.map(|cfg| [cfg.rise_time, cfg.drop_time, cfg.tilt_time]);
+    if found != Some(stored.map(Some)) {
+        anyhow::bail!("Unable to edit timings of shutter {} in the config", label);
+    }
+
+    // Keep the previous version, with the previous calibration.
+    let mut backup = filename.as_os_str().to_owned();
+    backup.push(".bak");
+    std::fs::copy(filename, &backup)?;
+
+    // Replace the file at once, the config watcher must not read it half
+    // written. The temporary file is in the same directory to be renamed.
+    let mut temporary = filename.as_os_str().to_owned();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, edited)?;
+    std::fs::set_permissions(&temporary, std::fs::metadata(filename)?.permissions())?;
+    std::fs::rename(&temporary, filename)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "\
+# Devices
+ground:
+  addr: 1
+  outputs:
+    light: {id: 1}
+upstairs:
+  # Replaced board
+  addr: 2
+
+  outputs:
+    light:
+      id: 1
+  shutters:
+    blind:
+      id: 1
+      down: 2 # motor
+      up: 3
+";
+
+    #[test]
+    fn key_line_nested() {
+        assert_eq!(key_line(SOURCE, &["ground"]), Some(2));
+        assert_eq!(key_line(SOURCE, &["upstairs", "addr"]), Some(8));
+        assert_eq!(
+            key_line(SOURCE, &["upstairs", "outputs", "light"]),
+            Some(11)
+        );
+        assert_eq!(
+            key_line(SOURCE, &["upstairs", "outputs", "light", "id"]),
+            Some(12)
+        );
+    }
+
+    #[test]
+    fn key_line_quoted() {
+        let source = "\"ground\":\n  'addr': 1\n  \"in puts\" : {}\n";
+        assert_eq!(key_line(source, &["ground"]), Some(1));
+        assert_eq!(key_line(source, &["ground", "addr"]), Some(2));
+        assert_eq!(key_line(source, &["ground", "in puts"]), Some(3));
+    }
+
+    #[test]
+    fn key_line_flow_style() {
+        // Deepest key written in the block style.
+        assert_eq!(
+            key_line(SOURCE, &["ground", "outputs", "light", "id"]),
+            Some(5)
+        );
+    }
+
+    #[test]
+    fn key_line_missing() {
+        assert_eq!(key_line(SOURCE, &["cellar"]), None);
+        // Comments are not keys.
+        assert_eq!(key_line(SOURCE, &["Devices"]), None);
+        // The deepest key found; `id` is nested deeper.
+        assert_eq!(key_line(SOURCE, &["upstairs", "id"]), Some(6));
+    }
+
+    #[test]
+    fn set_values_in_place() {
+        let values = [("down", "4".to_string()), ("rise_time", "50".to_string())];
+        let edited = set_values(SOURCE, &["upstairs", "shutters", "blind"], &values).unwrap();
+        let expected = SOURCE
+            .replace("down: 2 # motor", "down: 4 # motor")
+            .replace("up: 3\n", "up: 3\n      rise_time: 50\n");
+        assert_eq!(edited, expected);
+    }
+
+    #[test]
+    fn set_values_flow_style() {
+        let values = [("id", "2".to_string())];
+        assert!(set_values(SOURCE, &["ground", "outputs", "light"], &values).is_err());
+        assert!(set_values(SOURCE, &["cellar"], &values).is_err());
+    }
+
+    #[test]
+    fn store_shutter_timings_replaces_file() {
+        let dir = std::env::temp_dir().join(format!("io-gate-store-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let filename = dir.join("config.yaml");
+        let source = "\
+# Devices
+upstairs:
+  addr: 2
+  outputs: {}
+  inputs: {}
+  shutters:
+    blind:
+      id: 1 # west window
+      down: 2
+      up: 3
+";
+        std::fs::write(&filename, source).unwrap();
+
+        let first = Timings {
+            rise: 50.0,
+            drop: 52.004,
+            tilt: 1.5,
+        };
+        store_shutter_timings(&filename, 2, 1, &first).unwrap();
+        let stored = std::fs::read_to_string(&filename).unwrap();
+        assert!(stored.starts_with("# Devices\n"));
+        assert!(stored.contains("id: 1 # west window\n"));
+        let config: Config = serde_yaml::from_str(&stored).unwrap();
+        assert_eq!(config.shutter(2, 1).unwrap().drop_time, Some(52.0));
+
+        // The backup holds the previous calibration.
+        let second = Timings {
+            rise: 40.0,
+            ..first
+        };
+        store_shutter_timings(&filename, 2, 1, &second).unwrap();
+        let backup = std::fs::read_to_string(dir.join("config.yaml.bak")).unwrap();
+        assert_eq!(backup, stored);
+        assert!(!dir.join("config.yaml.tmp").exists());
+
+        assert!(store_shutter_timings(&filename, 1, 1, &first).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Problems found in a config source, as line and message.
+    fn problems(source: &str) -> Vec<(Option<usize>, String)> {
+        let config: Config = serde_yaml::from_str(source).unwrap();
+        config
+            .check(source)
+            .into_iter()
+            .map(|problem| (problem.line, problem.message))
+            .collect()
+    }
+
+    #[test]
+    fn check_diagnostics() {
+        let cases: &[(&str, &[(usize, &str)])] = &[
+            (
+                "\
+a:
+  addr: 1
+  outputs: {x: {id: 1}}
+  inputs: {}
+",
+                &[],
+            ),
+            (
+                "\
+a:
+  addr: 1
+  outputs: {}
+  inputs: {}
+b:
+  addr: 1
+  outputs: {}
+  inputs: {}
+",
+                &[
+                    (
+                        5,
+                        "Unique IDs of status sensors of b are already used by device a",
+                    ),
+                    (6, "Device b address 0x1 is already used by a"),
+                ],
+            ),
+            (
+                "\
+a:
+  addr: 63
+  outputs: {}
+  inputs: {}
+b:
+  addr: 64
+  outputs: {}
+  inputs: {}
+",
+                &[
+                    (2, "Device a uses the broadcast address 0x3f"),
+                    (6, "Device b address 0x40 is out of range (0-0x3e)"),
+                ],
+            ),
+            (
+                "\
+a:
+  addr: 1
+  outputs:
+    lamp:
+      id: 0
+  inputs:
+    button:
+      id: 0
+",
+                &[
+                    (5, "IO index 0 of lamp is reserved"),
+                    (8, "IO index 0 of button is reserved"),
+                ],
+            ),
+            (
+                "\
+a:
+  addr: 1
+  outputs:
+    lamp:
+      id: 1
+    fan:
+      id: 1
+  inputs:
+    lamp:
+      id: 2
+",
+                &[
+                    (
+                        6,
+                        "Unique ID io-gate-1-1 of fan is already used by lamp in device a",
+                    ),
+                    (7, "Index 1 of fan is already used by lamp"),
+                    (9, "Duplicated label lamp in device a"),
+                ],
+            ),
+            (
+                "\
+a:
+  addr: 1
+  outputs: {}
+  inputs: {}
+  shutters:
+    blind:
+      id: 1
+      down: 1
+      up: 2
+      rise_time: 70
+      drop_time: 0
+",
+                &[
+                    (10, "rise_time of shutter blind is over the longest 65.535s"),
+                    (11, "drop_time of shutter blind must be positive, not 0"),
+                ],
+            ),
+            (
+                "\
+gate:
+  mqtt_password: secret
+  mqtt_password_file: /run/secrets/mqtt
+",
+                &[(
+                    3,
+                    "mqtt_password and mqtt_password_file can't be used together",
+                )],
+            ),
+        ];
+        for (source, expected) in cases {
+            let expected: Vec<_> = expected
+                .iter()
+                .map(|(line, message)| (Some(*line), message.to_string()))
+                .collect();
+            assert_eq!(problems(source), expected, "{}", source);
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct IOs(#[serde(deserialize_with = "deserialize_ios")] HashMap<String, IOConfig>);
+
+    /// Indexes by label of IOs given in YAML.
+    fn ios(source: &str) -> Result<Vec<(String, u8)>, String> {
+        let ios: IOs = serde_yaml::from_str(source).map_err(|err| err.to_string())?;
+        let mut ios: Vec<_> = ios
+            .0
+            .into_iter()
+            .map(|(label, io)| (label, io.id))
+            .collect();
+        ios.sort_by_key(|(_, id)| *id);
+        Ok(ios)
+    }
+
+    fn labels(ios: &[(&str, u8)]) -> Result<Vec<(String, u8)>, String> {
+        Ok(ios
+            .iter()
+            .map(|(label, id)| (label.to_string(), *id))
+            .collect())
+    }
+
+    #[test]
+    fn io_range() {
+        assert_eq!(
+            ios("{count: 4, labels: [kitchen, hall, ~, office]}"),
+            labels(&[("kitchen", 1), ("hall", 2), ("office", 4)])
+        );
+        assert_eq!(
+            ios("{count: 3, first: 10, labels: [kitchen], template: 'out-{}'}"),
+            labels(&[("kitchen", 10), ("out-11", 11), ("out-12", 12)])
+        );
+        // Not labeled channels are skipped.
+        assert_eq!(ios("{count: 8}"), labels(&[]));
+    }
+
+    #[test]
+    fn io_range_type() {
+        let ios: IOs = serde_yaml::from_str("{count: 2, template: 'in-{}', type: door}").unwrap();
+        assert_eq!(ios.0.len(), 2);
+        assert!(ios
+            .0
+            .values()
+            .all(|io| io.io_type.as_deref() == Some("door")));
+    }
+
+    #[test]
+    fn io_range_with_labels() {
+        assert_eq!(
+            ios("{count: 2, labels: [a, b], fan: {id: 5}}"),
+            labels(&[("a", 1), ("b", 2), ("fan", 5)])
+        );
+        let err = ios("{count: 2, labels: [a, b], b: {id: 5}}").unwrap_err();
+        assert!(err.contains("Duplicated label b"), "{}", err);
+    }
+
+    #[test]
+    fn io_range_invalid() {
+        let err = ios("{count: 2, first: 255, template: 'out-{}'}").unwrap_err();
+        assert!(err.contains("out of range after 255"), "{}", err);
+        let err = ios("{count: 1, labels: [a, b]}").unwrap_err();
+        assert!(err.contains("2 labels given for 1 channels"), "{}", err);
+        // Reserved keys aren't labels.
+        let err = ios("{type: {id: 1}}").unwrap_err();
+        assert!(err.contains("expected a string"), "{}", err);
+        let err = ios("{labels: [a]}").unwrap_err();
+        assert!(err.contains("requires count"), "{}", err);
+    }
 }
@@ -1,4 +1,5 @@
 // Message parsing parts moved from io-ctrl. TODO: Move to a shared crate.
+use serde::{Deserialize, Serialize};
 
 // Input IO index. `0` is reserved to simplify things.
 pub type InIdx = u8;
@@ -10,7 +11,8 @@ pub type ProcIdx = u8;
 /// Higher level switch abstraction.
 /// eg. Activated -> LongActivated -> LongClick -> LongDeactivated -> Deactivated.
 /// Activated -> ShortClick -> Deactivated
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 #[repr(u8)]
 pub enum Trigger {
     /// Short click activation; longer than debounce period, but shorter than a
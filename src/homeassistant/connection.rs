@@ -1,17 +1,116 @@
-use super::{Incoming, Outgoing};
-use crate::consts;
+use super::{discovery, CalibrationCommand, CoverCommand, Incoming, Outgoing, Topics};
+use crate::api;
+use crate::config::Secret;
+use crate::consts::Trigger;
+use crate::message::{args::OutputChangeRequest, MessageRaw};
+use crate::shutters::Direction;
+use anyhow::{bail, Context};
 use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
-use rumqttc::{Event, Packet};
+use rumqttc::{Event, LastWill, Packet, SubscribeFilter, TlsConfiguration, Transport};
+use serde::Deserialize;
 use std::time::Duration;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tokio::{sync::Mutex, task};
 
 use tracing::{debug, error, info, warn};
 
+/// MQTT broker connection parameters.
+#[derive(Debug, Clone, Default)]
+pub struct Broker {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: Secret,
+    /// Connect using TLS. The broker is verified against `ca_file`, or
+    /// against system roots when not given.
+    pub tls: bool,
+    /// PEM file with the CA certificate of the broker.
+    pub ca_file: Option<String>,
+    /// PEM files with the client certificate and its private key for mutual
+    /// TLS authentication.
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    /// Tunnel MQTT over WebSocket (ws:// or wss:// with TLS).
+    pub websocket: bool,
+    /// HTTP path of the broker WebSocket endpoint.
+    pub ws_path: String,
+}
+
+impl Broker {
+    /// TLS configuration built from the certificate files.
+    fn tls_config(&self) -> anyhow::Result<TlsConfiguration> {
+        let client_auth = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Some((read_pem(cert)?, read_pem(key)?)),
+            (None, None) => None,
+            _ => bail!("Client certificate and key must be given together"),
+        };
+
+        match &self.ca_file {
+            Some(ca_file) => Ok(TlsConfiguration::Simple {
+                ca: read_pem(ca_file)?,
+                alpn: None,
+                client_auth,
+            }),
+            None if client_auth.is_some() => {
+                bail!("Client certificate authentication requires a CA file")
+            }
+            None => Ok(TlsConfiguration::default()),
+        }
+    }
+
+    /// Transport with the address of the broker it expects.
+    fn transport(&self) -> anyhow::Result<(Transport, String)> {
+        if self.websocket {
+            return self.websocket_transport();
+        }
+        if self.tls {
+            Ok((
+                Transport::tls_with_config(self.tls_config()?),
+                self.host.clone(),
+            ))
+        } else {
+            Ok((Transport::tcp(), self.host.clone()))
+        }
+    }
+
+    /// WebSocket transport is addressed with an URL instead of a host.
+    #[cfg(feature = "websocket")]
+    fn websocket_transport(&self) -> anyhow::Result<(Transport, String)> {
+        let scheme = if self.tls { "wss" } else { "ws" };
+        let url = format!("{}://{}:{}{}", scheme, self.host, self.port, self.ws_path);
+        if self.tls {
+            Ok((Transport::wss_with_config(self.tls_config()?), url))
+        } else {
+            Ok((Transport::ws(), url))
+        }
+    }
+
+    #[cfg(not(feature = "websocket"))]
+    fn websocket_transport(&self) -> anyhow::Result<(Transport, String)> {
+        bail!("MQTT over WebSocket requires building with the `websocket` feature")
+    }
+}
+
+/// Read a PEM file for the TLS configuration.
+fn read_pem(path: &str) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("Unable to read {}", path))
+}
+
+/// Capacity of the MQTT client request queue. Requests only enter it while
+/// connected, so it mostly absorbs bursts like discovery.
+const REQUEST_CAPACITY: usize = 1000;
+/// Limit of the MQTT packet size in both directions.
+const MAX_PACKET_SIZE: usize = 256 * 1024;
+/// Topics subscribed with a single request.
+const SUBSCRIBE_BATCH: usize = 32;
+/// Delay bounds between reconnection attempts.
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
 pub struct Initiator {
     client: AsyncClient,
     event_loop: EventLoop,
-    base: String,
+    topics: Topics,
 }
 
 /// HA interfacing via MQTT
@@ -20,82 +119,64 @@ pub struct HomeAssistant {
     outgoing: mpsc::Sender<Outgoing>,
     /// Incoming event queue: commands read from HA.
     incoming: Mutex<mpsc::Receiver<Incoming>>,
+    /// Topic layout used for publishing and subscriptions.
+    topics: Topics,
 }
 
 impl Initiator {
-    pub async fn new(id: &str, host: &str, port: u16, username: &str, password: &str) -> Self {
-        let mut mqttoptions = MqttOptions::new(id, host, port);
+    pub async fn new(topics: Topics, broker: &Broker) -> anyhow::Result<Self> {
+        let (transport, address) = broker.transport()?;
+        let mut mqttoptions = MqttOptions::new(topics.client_id(), address, broker.port);
         mqttoptions.set_keep_alive(Duration::from_secs(5));
-        mqttoptions.set_credentials(username, password);
-
-        let (client, mut event_loop) = AsyncClient::new(mqttoptions, 10);
-        client
-            .subscribe("hello/rumqtt", QoS::AtMostOnce)
-            .await
-            .unwrap();
+        mqttoptions.set_credentials(&broker.username, &broker.password.0);
+        mqttoptions.set_transport(transport);
+        // Broker marks the gate unavailable when the connection drops.
+        mqttoptions.set_last_will(LastWill::new(
+            topics.gate_status(),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        // Discovery of a device with many entities exceeds the 10KiB default.
+        mqttoptions.set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
 
-        // Fail early if parameters are invalid.
-        let initial_result = event_loop.poll().await;
-        if initial_result.is_err() {
-            warn!("Initial connection to MQTT failed. Check connection parameters");
-            panic!("Unable to contact MQTT");
-        }
+        // Connection is established (and re-established) by the receiver.
+        let (client, event_loop) = AsyncClient::new(mqttoptions, REQUEST_CAPACITY);
 
-        Initiator {
+        Ok(Initiator {
             client,
             event_loop,
-            base: "io-gate/".into(),
-        }
+            topics,
+        })
     }
 
-    /// Configure topics prefixes.
-    pub async fn set_topics(&mut self, base: &str, discover_prefix: &str) {
-        // TODO: Handle when reading state changes from HA
-        self.client
-            .subscribe(discover_prefix, QoS::AtMostOnce)
-            .await
-            .unwrap();
-        self.base = base.to_string();
-    }
-
-    async fn receiver(mut event_loop: EventLoop, queue: mpsc::Sender<Incoming>) {
+    /// Polls the MQTT event loop, reconnecting with backoff on errors, and
+    /// reports connection changes to the sender.
+    async fn receiver(
+        mut event_loop: EventLoop,
+        queue: mpsc::Sender<Incoming>,
+        topics: Topics,
+        connected: watch::Sender<bool>,
+    ) {
+        let mut backoff = RECONNECT_MIN;
         loop {
             let notification = event_loop.poll().await;
             let result = match notification {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker");
+                    backoff = RECONNECT_MIN;
+                    connected.send_replace(true);
+                    continue;
+                }
                 Ok(Event::Incoming(Packet::Publish(msg))) => {
                     // This can be ON/OFF messaging
                     info!(
                         "RX message on {} with payload '{:?}'",
                         msg.topic, msg.payload
                     );
-                    let topic = &msg.topic;
-                    let parts: Vec<&str> = topic.split("/").collect();
-                    // Maybe regexp instead?
-                    if parts.len() == 5
-                        && parts[0] == "smartenough"
-                        && parts[2] == "switch"
-                        && parts[4] == "set"
-                    {
-                        // This is a command setting output to particular value.
-                        let device = parts[1].parse::<u8>();
-                        if device.is_err() {
-                            warn!("Device address is not a 0-255 number: {}", parts[1]);
-                            continue;
-                        }
-                        let device = device.unwrap();
-
-                        let output = parts[3].parse::<u8>();
-                        if output.is_err() {
-                            warn!("Output index is not a 0-255 number: {}", parts[3]);
-                            continue;
-                        }
-                        let output = output.unwrap();
-                        let on = msg.payload == "ON";
-
-                        let message = Incoming::SetOutput { device, output, on };
+                    if let Some(message) = parse_command(&topics, &msg.topic, &msg.payload) {
                         queue.send(message).await
                     } else {
-                        info!("Unknown topic - ignoring");
                         continue;
                     }
                 }
@@ -106,10 +187,17 @@ impl Initiator {
                     // Silence common messages
                     continue;
                 }
-                _ => {
+                Ok(_) => {
                     info!("Received other message = {:?}", notification);
                     continue;
                 }
+                Err(err) => {
+                    connected.send_if_modified(|connected| std::mem::replace(connected, false));
+                    warn!("MQTT connection error: {}. Retrying in {:?}", err, backoff);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(RECONNECT_MAX);
+                    continue;
+                }
             };
             if result.is_err() {
                 error!(
@@ -121,69 +209,70 @@ impl Initiator {
         }
     }
 
-    async fn sender(client: AsyncClient, mut queue: mpsc::Receiver<Outgoing>) {
+    /// Publishes outgoing messages. While disconnected, messages wait in a
+    /// backlog; all subscriptions are renewed after reconnecting.
+    async fn sender(
+        client: AsyncClient,
+        mut queue: mpsc::Receiver<Outgoing>,
+        topics: Topics,
+        mut connected: watch::Receiver<bool>,
+    ) {
+        let mut backlog = Backlog::default();
+        // Session is not persistent - broker forgets subscriptions on disconnect.
+        let mut subscriptions: Vec<String> = Vec::new();
+        let mut retry = tokio::time::interval(Duration::from_secs(1));
         loop {
-            if let Some(command) = queue.recv().await {
-                match command {
-                    Outgoing::Subscribe(topic) => {
-                        let result = client.subscribe(&topic, QoS::AtMostOnce).await;
-                        if result.is_err() {
-                            warn!("Unable to subscribe to a topic {}. Hard fail", topic);
-                            panic!("Unable to continue");
+            tokio::select! {
+                command = queue.recv() => {
+                    let Some(command) = command else {
+                        // Channel end closed - quit.
+                        return;
+                    };
+                    match encode(&topics, command) {
+                        Request::Subscribe(topic) => {
+                            if !subscriptions.contains(&topic) {
+                                subscriptions.push(topic.clone());
+                            }
+                            backlog.subscribe(topic);
                         }
-                    }
-                    Outgoing::Initial => {
-                        client
-                            .publish(
-                                "smartenough/status",
-                                QoS::AtLeastOnce,
-                                false,
-                                "daemon started",
-                            )
-                            .await
-                            .expect("Initial message should publish fine");
-                    }
-                    Outgoing::RawTest(raw) => {
-                        client
-                            .publish("hello/rumqtt", QoS::AtLeastOnce, false, raw)
-                            .await
-                            .expect("Test should work");
-                    }
-                    Outgoing::DiscoveryDevice(msg) => {
-                        let topic = format!(
-                            "{}/device/{}/config",
-                            consts::HA_DISCOVERY_TOPIC,
-                            msg.device.identifiers[0]
-                        );
-                        let payload = msg.serialize();
-                        debug!("Sending discovery payload to {}: {}", topic, payload);
-                        let result = client
-                            .publish(topic, QoS::AtLeastOnce, false, payload)
-                            .await;
-                        if result.is_err() {
-                            error!("Unable to publish discovery message {:?}", result);
+                        Request::Unsubscribe(topic) => {
+                            subscriptions.retain(|subscribed| *subscribed != topic);
+                            backlog.unsubscribe(topic);
                         }
+                        Request::Publish {
+                            topic,
+                            payload,
+                            retain,
+                        } => backlog.publish(topic, payload, retain),
+                        Request::Event { topic, payload } => {
+                            if *connected.borrow() {
+                                backlog.publish(topic, payload, false);
+                            } else {
+                                debug!("Dropping event to {} while disconnected", topic);
+                            }
+                        }
+                    }
+                }
+                changed = connected.changed() => {
+                    if changed.is_err() {
+                        // Receiver quit.
+                        return;
                     }
-                    Outgoing::OutputChanged { device, output, on } => {
-                        let topic = format!(
-                            "{}/{}/switch/{}/state",
-                            consts::HA_CONTROL_TOPIC,
-                            device,
-                            output
-                        );
-                        let payload = if on { "ON" } else { "OFF" };
-                        debug!("Sending state payload to {}: {}", topic, payload);
-                        let result = client
-                            .publish(topic, QoS::AtLeastOnce, false, payload)
-                            .await;
-                        if result.is_err() {
-                            error!("Unable to publish state message {:?}", result);
+                    if *connected.borrow_and_update() {
+                        for topic in &subscriptions {
+                            backlog.subscribe(topic.clone());
                         }
+                        // Replace the last will published on disconnection.
+                        backlog.publish(topics.gate_status(), b"online".to_vec(), true);
+                    } else {
+                        warn!("MQTT disconnected, buffering state updates");
                     }
                 }
-            } else {
-                // Channel end closed - quit.
-                return;
+                _ = retry.tick() => {}
+            }
+
+            if *connected.borrow() {
+                backlog.flush(&client);
             }
         }
     }
@@ -191,12 +280,480 @@ impl Initiator {
     pub async fn start(self) -> HomeAssistant {
         let (out_sender, out_receiver) = mpsc::channel::<Outgoing>(10);
         let (in_sender, in_receiver) = mpsc::channel::<Incoming>(10);
-        task::spawn(Self::receiver(self.event_loop, in_sender));
-        task::spawn(Self::sender(self.client, out_receiver));
+        let (connected_sender, connected_receiver) = watch::channel(false);
+        task::spawn(Self::receiver(
+            self.event_loop,
+            in_sender,
+            self.topics.clone(),
+            connected_sender,
+        ));
+        task::spawn(Self::sender(
+            self.client,
+            out_receiver,
+            self.topics.clone(),
+            connected_receiver,
+        ));
 
         HomeAssistant {
             outgoing: out_sender,
             incoming: Mutex::new(in_receiver),
+            topics: self.topics,
+        }
+    }
+}
+
+/// MQTT request built from an Outgoing message.
+enum Request {
+    Subscribe(String),
+    Unsubscribe(String),
+    /// State publish; only the latest payload per topic matters. Retained
+    /// state is available to clients as soon as they subscribe.
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+        retain: bool,
+    },
+    /// Momentary event; dropped when it can't be delivered right away.
+    Event {
+        topic: String,
+        payload: Vec<u8>,
+    },
+}
+
+/// Translate an Outgoing message to the MQTT request.
+fn encode(topics: &Topics, command: Outgoing) -> Request {
+    match command {
+        Outgoing::Subscribe(topic) => Request::Subscribe(topic),
+        Outgoing::Unsubscribe(topic) => Request::Unsubscribe(topic),
+        Outgoing::Initial => Request::Publish {
+            topic: topics.gate_status(),
+            payload: b"online".to_vec(),
+            retain: true,
+        },
+        Outgoing::ApiEvent { device, message } => {
+            let event = api::Event {
+                addr: device,
+                message: &message,
+            };
+            let payload = serde_json::to_string(&event).expect("Messages are serializable");
+            Request::Event {
+                topic: topics.api_events(),
+                payload: payload.into_bytes(),
+            }
+        }
+        Outgoing::GateStats(stats) => {
+            let topic = topics.gate_stats();
+            let payload = serde_json::to_string(&stats).expect("Stats are serializable");
+            debug!("Sending gate stats to {}: {}", topic, payload);
+            Request::Publish {
+                topic,
+                payload: payload.into_bytes(),
+                retain: true,
+            }
+        }
+        Outgoing::RawFrame(raw) => Request::Event {
+            topic: topics.raw_rx(),
+            payload: raw.to_text().into_bytes(),
+        },
+        Outgoing::DiscoveryDevice(msg) => {
+            let topic = topics.discovery_config(&msg.device.identifiers[0]);
+            let payload = msg.serialize();
+            debug!("Sending discovery payload to {}: {}", topic, payload);
+            // Retained, so stale components can be found after restart.
+            Request::Publish {
+                topic,
+                payload: payload.into_bytes(),
+                retain: true,
+            }
+        }
+        Outgoing::DiscoveryRemoved(identifier) => Request::Publish {
+            // Empty payload removes the device and clears the retained config.
+            topic: topics.discovery_config(&identifier),
+            payload: Vec::new(),
+            retain: true,
+        },
+        Outgoing::OutputChanged { device, output, on } => {
+            let topic = topics.output_state(device, output);
+            let payload = state_payload(on);
+            debug!("Sending state payload to {}: {}", topic, payload);
+            Request::Publish {
+                topic,
+                payload: payload.into(),
+                retain: true,
+            }
+        }
+        Outgoing::InputChanged { device, input, on } => {
+            let topic = topics.input_state(device, input);
+            let payload = state_payload(on);
+            debug!("Sending input state payload to {}: {}", topic, payload);
+            Request::Publish {
+                topic,
+                payload: payload.into(),
+                retain: true,
+            }
+        }
+        Outgoing::DeviceStatus {
+            device,
+            uptime,
+            errors,
+            warnings,
+        } => {
+            let topic = topics.device_status(device);
+            let payload = serde_json::json!({
+                "uptime": uptime,
+                "errors": errors,
+                "warnings": warnings,
+            })
+            .to_string();
+            debug!("Sending device status to {}: {}", topic, payload);
+            Request::Publish {
+                topic,
+                payload: payload.into_bytes(),
+                retain: true,
+            }
+        }
+        Outgoing::ShutterChanged {
+            device,
+            shutter,
+            direction,
+            position,
+        } => {
+            let topic = topics.shutter(device, shutter, "state");
+            let height = position.height.percent();
+            let state = match direction {
+                Some(Direction::Up) => "opening",
+                Some(Direction::Down) => "closing",
+                None if height == 0 => "open",
+                None if height == 100 => "closed",
+                None => "stopped",
+            };
+            // HA uses 100 as open, shutters use 0.
+            let payload = serde_json::json!({
+                "state": state,
+                "position": 100 - height,
+                "tilt": 100 - position.tilt.percent(),
+                "position_known": position.height.is_known(),
+                "tilt_known": position.tilt.is_known(),
+            })
+            .to_string();
+            debug!("Sending shutter state to {}: {}", topic, payload);
+            Request::Publish {
+                topic,
+                payload: payload.into_bytes(),
+                retain: true,
+            }
+        }
+        Outgoing::InputTriggered {
+            device,
+            input,
+            trigger,
+        } => {
+            let topic = topics.input_event(device, input);
+            let payload = serde_json::json!({ "event_type": trigger.name() }).to_string();
+            debug!("Sending input event to {}: {}", topic, payload);
+            Request::Event {
+                topic,
+                payload: payload.into_bytes(),
+            }
+        }
+    }
+}
+
+/// ON/OFF payload of a binary state; `None` resets HA entity to unknown.
+fn state_payload(on: Option<bool>) -> &'static str {
+    match on {
+        Some(true) => "ON",
+        Some(false) => "OFF",
+        None => "None",
+    }
+}
+
+/// Requests waiting for the broker connection. Publishes to the same topic
+/// are coalesced, keeping only the latest payload.
+#[derive(Default)]
+struct Backlog {
+    subscriptions: Vec<String>,
+    unsubscriptions: Vec<String>,
+    publishes: Vec<(String, Vec<u8>, bool)>,
+}
+
+impl Backlog {
+    fn subscribe(&mut self, topic: String) {
+        self.unsubscriptions.retain(|other| *other != topic);
+        if !self.subscriptions.contains(&topic) {
+            self.subscriptions.push(topic);
+        }
+    }
+
+    fn unsubscribe(&mut self, topic: String) {
+        // The broker may know the topic from an earlier subscription.
+        self.subscriptions.retain(|subscribed| *subscribed != topic);
+        if !self.unsubscriptions.contains(&topic) {
+            self.unsubscriptions.push(topic);
+        }
+    }
+
+    fn publish(&mut self, topic: String, payload: Vec<u8>, retain: bool) {
+        match self.publishes.iter_mut().find(|(t, _, _)| *t == topic) {
+            Some(entry) => *entry = (topic, payload, retain),
+            None => self.publishes.push((topic, payload, retain)),
+        }
+    }
+
+    /// Hand requests over to the MQTT client without blocking. Whatever does
+    /// not fit stays for the next try.
+    fn flush(&mut self, client: &AsyncClient) {
+        // Many topics in one request would exceed the maximum packet size.
+        while !self.subscriptions.is_empty() {
+            let count = self.subscriptions.len().min(SUBSCRIBE_BATCH);
+            let filters = self.subscriptions[..count]
+                .iter()
+                .map(|topic| SubscribeFilter::new(topic.clone(), QoS::AtMostOnce));
+            if let Err(err) = client.try_subscribe_many(filters) {
+                warn!("Unable to subscribe, will retry: {}", err);
+                return;
+            }
+            self.subscriptions.drain(..count);
+        }
+        while let Some(topic) = self.unsubscriptions.first() {
+            if let Err(err) = client.try_unsubscribe(topic) {
+                warn!("Unable to unsubscribe from {}, will retry: {}", topic, err);
+                return;
+            }
+            self.unsubscriptions.remove(0);
+        }
+
+        let mut sent = 0;
+        for (topic, payload, retain) in &self.publishes {
+            if let Err(err) = client.try_publish(topic, QoS::AtLeastOnce, *retain, payload.clone())
+            {
+                warn!("Unable to publish to {}, will retry: {}", topic, err);
+                break;
+            }
+            sent += 1;
+        }
+        self.publishes.drain(..sent);
+    }
+}
+
+/// Parse an index part of the topic.
+fn parse_idx(part: &str, what: &str) -> Option<u8> {
+    match part.parse::<u8>() {
+        Ok(idx) => Some(idx),
+        Err(_) => {
+            warn!("{} is not a 0-255 number: {}", what, part);
+            None
+        }
+    }
+}
+
+/// Parse HA percentage payload (0-100).
+fn parse_percent(payload: &[u8]) -> Option<u8> {
+    let value = std::str::from_utf8(payload)
+        .ok()
+        .and_then(|text| text.trim().parse::<u8>().ok());
+    match value {
+        Some(value) if value <= 100 => Some(value),
+        _ => {
+            warn!("Invalid percentage payload {:?}", payload);
+            None
+        }
+    }
+}
+
+/// Parse output command: ON, OFF, TOGGLE or a JSON like
+/// `{"state": "ON", "duration": 30}` with the duration in seconds.
+fn parse_output_request(payload: &[u8]) -> Option<(OutputChangeRequest, Option<Duration>)> {
+    #[derive(Deserialize)]
+    struct Timed {
+        state: String,
+        duration: Option<f32>,
+    }
+
+    let parse_state = |state: &[u8]| match state {
+        b"ON" => Some(OutputChangeRequest::On),
+        b"OFF" => Some(OutputChangeRequest::Off),
+        b"TOGGLE" => Some(OutputChangeRequest::Toggle),
+        _ => None,
+    };
+
+    let parsed = if payload.starts_with(b"{") {
+        serde_json::from_slice::<Timed>(payload)
+            .ok()
+            .and_then(|timed| {
+                let state = parse_state(timed.state.as_bytes())?;
+                let duration = match timed.duration {
+                    Some(secs) => Some(Duration::try_from_secs_f32(secs).ok()?),
+                    None => None,
+                };
+                Some((state, duration))
+            })
+    } else {
+        parse_state(payload).map(|state| (state, None))
+    };
+
+    if parsed.is_none() {
+        warn!("Invalid output command {:?}", payload);
+    }
+    parsed
+}
+
+/// Translate message received on a command topic to an Incoming command.
+fn parse_command(topics: &Topics, topic: &str, payload: &[u8]) -> Option<Incoming> {
+    if topic == topics.ha_status() {
+        return if payload == b"online" {
+            Some(Incoming::HomeAssistantOnline)
+        } else {
+            info!("Home Assistant status: {:?}", payload);
+            None
+        };
+    }
+
+    if topic == topics.raw_inject() {
+        let raw = std::str::from_utf8(payload)
+            .ok()
+            .and_then(MessageRaw::from_text);
+        if raw.is_none() {
+            warn!("Invalid raw frame {:?}, expected ID#DATA in hex", payload);
+        }
+        return raw.map(Incoming::RawFrame);
+    }
+
+    if topic == topics.api_command() {
+        return match serde_json::from_slice::<api::Command>(payload) {
+            // Shutter commands pass the position tracking.
+            Ok(api::Command::Shutter { addr, shutter, cmd }) => Some(Incoming::Shutter {
+                device: addr,
+                shutter,
+                cmd: CoverCommand::Driver(cmd),
+            }),
+            Ok(command) => {
+                let (device, message) = command.to_message();
+                Some(Incoming::Api { device, message })
+            }
+            Err(err) => {
+                warn!("Invalid API command {:?}: {}", payload, err);
+                None
+            }
+        };
+    }
+
+    if let Some(identifier) = topics.discovery_identifier(topic) {
+        // Devices of other gates and removed devices are not ours to clean.
+        if topics.device_addr(identifier).is_none() || payload.is_empty() {
+            return None;
+        }
+        let announced = discovery::Announced::parse(payload);
+        if announced.is_none() {
+            warn!("Unable to parse discovery of {}", identifier);
+        }
+        return announced.map(Incoming::Announced);
+    }
+
+    // Command topics: <base>/<addr>/<kind>/<idx>/<action...>
+    let base = format!("{}/", topics.base());
+    let parts: Vec<&str> = match topic.strip_prefix(&base) {
+        Some(rest) => rest.split("/").collect(),
+        None => {
+            info!("Unknown topic - ignoring");
+            return None;
+        }
+    };
+    if parts.len() < 4 {
+        info!("Unknown topic - ignoring");
+        return None;
+    }
+
+    let device = parse_idx(parts[0], "Device address")?;
+    match (parts[1], &parts[3..]) {
+        ("switch", ["set"]) => {
+            // This is a command setting output to particular value.
+            let output = parse_idx(parts[2], "Output index")?;
+            let (state, duration) = parse_output_request(payload)?;
+            Some(Incoming::SetOutput {
+                device,
+                output,
+                state,
+                duration,
+            })
+        }
+        ("input", ["trigger"]) => {
+            let input = parse_idx(parts[2], "Input index")?;
+            let trigger = std::str::from_utf8(payload)
+                .ok()
+                .and_then(|name| Trigger::from_name(name.trim()));
+            let trigger = if let Some(trigger) = trigger {
+                trigger
+            } else {
+                warn!("Unknown input trigger {:?}", payload);
+                return None;
+            };
+            Some(Incoming::TriggerInput {
+                device,
+                input,
+                trigger,
+            })
+        }
+        ("procedure", ["call"]) => {
+            let proc_id = parse_idx(parts[2], "Procedure index")?;
+            Some(Incoming::CallProcedure { device, proc_id })
+        }
+        ("shutter", ["set"]) => {
+            let shutter = parse_idx(parts[2], "Shutter index")?;
+            let cmd = match payload {
+                b"OPEN" => CoverCommand::Open,
+                b"CLOSE" => CoverCommand::Close,
+                b"STOP" => CoverCommand::Stop,
+                _ => {
+                    warn!("Unknown cover command {:?}", payload);
+                    return None;
+                }
+            };
+            Some(Incoming::Shutter {
+                device,
+                shutter,
+                cmd,
+            })
+        }
+        ("shutter", ["position", "set"]) => {
+            let shutter = parse_idx(parts[2], "Shutter index")?;
+            // HA: 100 is open; shutters: 0 is open.
+            let position = 100 - parse_percent(payload)?;
+            Some(Incoming::Shutter {
+                device,
+                shutter,
+                cmd: CoverCommand::Position(position),
+            })
+        }
+        ("shutter", ["calibrate"]) => {
+            let shutter = parse_idx(parts[2], "Shutter index")?;
+            let cmd = match payload {
+                b"START" => CalibrationCommand::Start,
+                b"MARK" => CalibrationCommand::Mark,
+                b"CANCEL" => CalibrationCommand::Cancel,
+                _ => {
+                    warn!("Unknown calibration command {:?}", payload);
+                    return None;
+                }
+            };
+            Some(Incoming::ShutterCalibration {
+                device,
+                shutter,
+                cmd,
+            })
+        }
+        ("shutter", ["tilt", "set"]) => {
+            let shutter = parse_idx(parts[2], "Shutter index")?;
+            let tilt = 100 - parse_percent(payload)?;
+            Some(Incoming::Shutter {
+                device,
+                shutter,
+                cmd: CoverCommand::Tilt(tilt),
+            })
+        }
+        _ => {
+            info!("Unknown topic - ignoring");
+            None
         }
     }
 }
@@ -214,4 +771,14 @@ impl HomeAssistant {
         self.outgoing.send(msg).await?;
         Ok(())
     }
+
+    /// Topic layout this connection was set up with.
+    pub fn topics(&self) -> &Topics {
+        &self.topics
+    }
+
+    /// Messages waiting to be published.
+    pub fn queue_depth(&self) -> usize {
+        self.outgoing.max_capacity() - self.outgoing.capacity()
+    }
 }
@@ -1,8 +1,9 @@
-use crate::{config, consts};
-use serde::Serialize;
+use super::{Outgoing, Topics};
+use crate::{config, consts, consts::Trigger};
+use serde::{Deserialize, Serialize};
 use serde_json;
 use std::collections::{HashMap, HashSet};
-use tracing::error;
+use tracing::{error, info, warn};
 
 /// Device identifier
 #[derive(Serialize, Debug, Default)]
@@ -11,7 +12,15 @@ pub struct DeviceId {
     /// Device CAN bus ID + CAN Address to uniquely identify device.
     pub identifiers: Vec<String>,
     pub manufacturer: String,
-    // hw, sw, ...
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sw_version: Option<String>,
+    /// Area assigned by HA when the device is first discovered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggested_area: Option<String>,
+    /// Identifier of the device this one is connected through.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub via_device: Option<String>,
+    // hw, ...
 }
 
 /// Discovery origin - this software identifier.
@@ -23,56 +32,406 @@ pub struct Origin {
 }
 
 /// Represents a component - a part of Device defined by Discovery
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Default)]
 pub struct Component {
-    pub name: String,
     pub platform: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     /// Changes icon; outlet or switch
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub device_class: Option<String>,
-    // pub icon: String,
-    pub unique_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unique_id: Option<String>,
+    /// `config` or `diagnostic` for entities not used in daily control.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub command_topic: Option<String>,
-    pub state_topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_topic: Option<String>,
+
+    // Cover fields.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub set_position_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tilt_command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tilt_status_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tilt_status_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
+
+    // Payload overrides for platforms not using ON/OFF by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_open: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_close: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_open: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_closed: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_lock: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_unlock: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_locked: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_unlocked: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_on: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_off: Option<String>,
+
+    // Sensor fields.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit_of_measurement: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_class: Option<String>,
+
+    /// Button: payload sent on press.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_press: Option<String>,
+
+    /// Event entity: list of event types it can fire.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_types: Option<Vec<String>>,
+
+    // Device automation (trigger) fields.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub automation_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub trigger_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subtype: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_template: Option<String>,
+}
+
+/// HA platform used for an output, selected by the `type` in config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputKind {
+    Switch,
+    Outlet,
+    Light,
+    Fan,
+    Valve,
+    Lock,
 }
 
+impl OutputKind {
+    /// Parse `type` from the output config. Missing type is a switch.
+    pub fn from_config(io_type: Option<&str>) -> Option<Self> {
+        Some(match io_type {
+            None | Some("switch") => Self::Switch,
+            Some("outlet") => Self::Outlet,
+            Some("light") => Self::Light,
+            Some("fan") => Self::Fan,
+            Some("valve") => Self::Valve,
+            Some("lock") => Self::Lock,
+            Some(_) => return None,
+        })
+    }
+}
+
+/// HA binary_sensor device classes, selected by the input `type` in config.
+/// See https://www.home-assistant.io/integrations/binary_sensor/
+pub const INPUT_CLASSES: [&str; 28] = [
+    "battery",
+    "battery_charging",
+    "carbon_monoxide",
+    "cold",
+    "connectivity",
+    "door",
+    "garage_door",
+    "gas",
+    "heat",
+    "light",
+    "lock",
+    "moisture",
+    "motion",
+    "moving",
+    "occupancy",
+    "opening",
+    "plug",
+    "power",
+    "presence",
+    "problem",
+    "running",
+    "safety",
+    "smoke",
+    "sound",
+    "tamper",
+    "update",
+    "vibration",
+    "window",
+];
+
 impl Component {
-    pub fn new_switch(name: &str, device_addr: u8, idx: u8, device_class: Option<String>) -> Self {
-        Self {
-            name: name.to_string(),
-            platform: "switch".to_string(),
-            device_class: Some(device_class.unwrap_or("switch".to_string())),
-            // icon: "mdi:light".to_string(),
-            unique_id: format!("io-gate-{}-{}", device_addr, idx),
-            command_topic: Some(format!(
-                "{}/{}/switch/{}/set",
-                consts::HA_CONTROL_TOPIC,
-                device_addr,
-                idx
-            )),
-            state_topic: format!(
-                "{}/{}/switch/{}/state",
-                consts::HA_CONTROL_TOPIC,
-                device_addr,
-                idx
-            ),
+    /// Apply icon and entity category configured for the IO.
+    fn set_metadata(&mut self, io: &config::IOConfig, label: &str, device: &str) {
+        self.icon = io.icon.clone();
+        match io.entity_category.as_deref() {
+            None => {}
+            Some(category @ ("config" | "diagnostic")) => {
+                self.entity_category = Some(category.to_string());
+            }
+            Some(category) => {
+                error!(
+                    "Unknown entity category {:?} of {} in device {:?}, ignoring",
+                    category, label, device
+                );
+            }
         }
     }
 
-    pub fn new_input(name: &str, device_addr: u8, idx: u8, device_class: Option<String>) -> Self {
-        // Class list: https://www.home-assistant.io/integrations/binary_sensor/
+    /// Create an output component. All platforms share the ON/OFF payloads
+    /// on the wire; platforms with other defaults are told to use them.
+    pub fn new_output(
+        topics: &Topics,
+        name: &str,
+        unique_id: String,
+        device_addr: u8,
+        idx: u8,
+        kind: OutputKind,
+    ) -> Self {
+        let mut component = Self {
+            name: Some(name.to_string()),
+            unique_id: Some(unique_id),
+            command_topic: Some(topics.output_command(device_addr, idx)),
+            state_topic: Some(topics.output_state(device_addr, idx)),
+            ..Default::default()
+        };
+
+        match kind {
+            OutputKind::Switch | OutputKind::Outlet => {
+                component.platform = "switch".to_string();
+                component.device_class = Some(if kind == OutputKind::Outlet {
+                    "outlet".to_string()
+                } else {
+                    "switch".to_string()
+                });
+            }
+            OutputKind::Light => {
+                component.platform = "light".to_string();
+            }
+            OutputKind::Fan => {
+                component.platform = "fan".to_string();
+            }
+            OutputKind::Valve => {
+                component.platform = "valve".to_string();
+                component.payload_open = Some("ON".to_string());
+                component.payload_close = Some("OFF".to_string());
+                component.state_open = Some("ON".to_string());
+                component.state_closed = Some("OFF".to_string());
+            }
+            OutputKind::Lock => {
+                // Electric strikes release the lock when energized.
+                component.platform = "lock".to_string();
+                component.payload_lock = Some("OFF".to_string());
+                component.payload_unlock = Some("ON".to_string());
+                component.state_locked = Some("OFF".to_string());
+                component.state_unlocked = Some("ON".to_string());
+            }
+        }
+        component
+    }
+
+    pub fn new_input(
+        topics: &Topics,
+        name: &str,
+        unique_id: String,
+        device_addr: u8,
+        idx: u8,
+        device_class: Option<String>,
+    ) -> Self {
         Self {
-            name: name.to_string(),
+            name: Some(name.to_string()),
             platform: "binary_sensor".to_string(),
-            device_class: device_class,
-            // icon: "mdi:light".to_string(),
-            unique_id: format!("io-gate-in-{}-{}", device_addr, idx),
-            command_topic: None,
-            state_topic: format!(
-                "{}/{}/switch/{}/state",
-                consts::HA_CONTROL_TOPIC,
-                device_addr,
-                idx
+            device_class,
+            unique_id: Some(unique_id),
+            state_topic: Some(topics.input_state(device_addr, idx)),
+            ..Default::default()
+        }
+    }
+
+    /// Shutter (blinds with tilt) driven by the device shutter driver.
+    pub fn new_cover(
+        topics: &Topics,
+        name: &str,
+        unique_id: String,
+        device_addr: u8,
+        idx: u8,
+    ) -> Self {
+        Self {
+            name: Some(name.to_string()),
+            platform: "cover".to_string(),
+            device_class: Some("blind".to_string()),
+            unique_id: Some(unique_id),
+            command_topic: Some(topics.shutter(device_addr, idx, "set")),
+            set_position_topic: Some(topics.shutter(device_addr, idx, "position/set")),
+            tilt_command_topic: Some(topics.shutter(device_addr, idx, "tilt/set")),
+            // Estimated state is published as a single JSON.
+            state_topic: Some(topics.shutter(device_addr, idx, "state")),
+            value_template: Some("{{ value_json.state }}".to_string()),
+            position_topic: Some(topics.shutter(device_addr, idx, "state")),
+            position_template: Some("{{ value_json.position }}".to_string()),
+            tilt_status_topic: Some(topics.shutter(device_addr, idx, "state")),
+            tilt_status_template: Some("{{ value_json.tilt }}".to_string()),
+            json_attributes_topic: Some(topics.shutter(device_addr, idx, "state")),
+            ..Default::default()
+        }
+    }
+
+    /// Button driving the shutter calibration procedure.
+    /// Action is START, MARK or CANCEL.
+    pub fn new_calibration_button(
+        topics: &Topics,
+        name: &str,
+        unique_id: String,
+        device_addr: u8,
+        idx: u8,
+        action: &str,
+    ) -> Self {
+        Self {
+            name: Some(format!("{} calibration {}", name, action.to_lowercase())),
+            platform: "button".to_string(),
+            unique_id: Some(unique_id),
+            entity_category: Some("config".to_string()),
+            command_topic: Some(topics.shutter(device_addr, idx, "calibrate")),
+            payload_press: Some(action.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Diagnostic sensor reading a field of the device Status message.
+    pub fn new_status_sensor(
+        topics: &Topics,
+        unique_id: String,
+        device_addr: u8,
+        field: &str,
+    ) -> Self {
+        let mut component = Self {
+            name: Some(field.to_string()),
+            platform: "sensor".to_string(),
+            unique_id: Some(unique_id),
+            entity_category: Some("diagnostic".to_string()),
+            state_topic: Some(topics.device_status(device_addr)),
+            value_template: Some(format!("{{{{ value_json.{} }}}}", field)),
+            state_class: Some("total_increasing".to_string()),
+            ..Default::default()
+        };
+        if field == "uptime" {
+            component.device_class = Some("duration".to_string());
+            component.unit_of_measurement = Some("s".to_string());
+            component.state_class = Some("measurement".to_string());
+        }
+        component
+    }
+
+    /// Diagnostic sensor reading a field of the gate stats.
+    pub fn new_gate_sensor(topics: &Topics, name: &str, field: &str) -> Self {
+        Self {
+            name: Some(name.to_string()),
+            platform: "sensor".to_string(),
+            unique_id: Some(topics.unique_id(&format!("gate-{}", field))),
+            entity_category: Some("diagnostic".to_string()),
+            state_topic: Some(topics.gate_stats()),
+            value_template: Some(format!("{{{{ value_json.{} }}}}", field)),
+            ..Default::default()
+        }
+    }
+
+    /// Button simulating an input trigger, as if the wall button was used.
+    pub fn new_input_button(
+        topics: &Topics,
+        name: &str,
+        unique_id: String,
+        device_addr: u8,
+        idx: u8,
+        trigger: Trigger,
+    ) -> Self {
+        Self {
+            name: Some(format!("{} {}", name, trigger.name().replace('_', " "))),
+            platform: "button".to_string(),
+            unique_id: Some(unique_id),
+            command_topic: Some(topics.input_trigger(device_addr, idx)),
+            payload_press: Some(trigger.name().to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Button calling a procedure programmed in the device VM.
+    pub fn new_procedure_button(
+        topics: &Topics,
+        name: &str,
+        unique_id: String,
+        device_addr: u8,
+        proc_id: u8,
+    ) -> Self {
+        Self {
+            name: Some(name.to_string()),
+            platform: "button".to_string(),
+            unique_id: Some(unique_id),
+            command_topic: Some(topics.procedure(device_addr, proc_id)),
+            payload_press: Some("PRESS".to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Event entity firing on every input trigger (short click, long click, ...)
+    pub fn new_input_event(
+        topics: &Topics,
+        name: &str,
+        unique_id: String,
+        device_addr: u8,
+        idx: u8,
+    ) -> Self {
+        Self {
+            name: Some(format!("{} button", name)),
+            platform: "event".to_string(),
+            device_class: Some("button".to_string()),
+            unique_id: Some(unique_id),
+            state_topic: Some(topics.input_event(device_addr, idx)),
+            event_types: Some(
+                Trigger::ALL
+                    .iter()
+                    .map(|trigger| trigger.name().to_string())
+                    .collect(),
             ),
+            ..Default::default()
+        }
+    }
+
+    /// Device automation trigger for a single input trigger type. Shares topic
+    /// with the event entity.
+    pub fn new_input_trigger(
+        topics: &Topics,
+        name: &str,
+        device_addr: u8,
+        idx: u8,
+        trigger: Trigger,
+    ) -> Self {
+        Self {
+            platform: "device_automation".to_string(),
+            automation_type: Some("trigger".to_string()),
+            topic: Some(topics.input_event(device_addr, idx)),
+            trigger_type: Some(trigger.name().to_string()),
+            subtype: Some(name.to_string()),
+            payload: Some(trigger.name().to_string()),
+            value_template: Some("{{ value_json.event_type }}".to_string()),
+            ..Default::default()
         }
     }
 }
@@ -95,17 +454,346 @@ impl Discovery {
     }
 }
 
-pub fn new_device(name: &str, config: &config::DeviceConfig) -> Discovery {
-    let origin = Origin {
+/// Device discovery read back from the broker, as published earlier.
+#[derive(Debug, Clone)]
+pub struct Announced {
+    pub identifier: String,
+    /// Component ID -> platform of components still active. Components
+    /// already removed (announced with the platform alone) are skipped.
+    pub components: HashMap<String, String>,
+}
+
+impl Announced {
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        #[derive(Deserialize)]
+        struct Device {
+            identifiers: Vec<String>,
+        }
+        #[derive(Deserialize)]
+        struct Raw {
+            device: Device,
+            #[serde(default)]
+            components: HashMap<String, serde_json::Map<String, serde_json::Value>>,
+        }
+
+        let raw: Raw = serde_json::from_slice(payload).ok()?;
+        let identifier = raw.device.identifiers.into_iter().next()?;
+        let components = raw
+            .components
+            .into_iter()
+            .filter(|(_, fields)| fields.len() > 1)
+            .filter_map(|(id, fields)| {
+                let platform = fields.get("platform")?.as_str()?.to_string();
+                Some((id, platform))
+            })
+            .collect();
+        Some(Announced {
+            identifier,
+            components,
+        })
+    }
+}
+
+/// Discovery updates removing what was announced earlier but is no longer in
+/// the config: stale components are re-announced with the platform alone and
+/// devices gone from the config are removed entirely.
+///
+/// HA ignores a unique ID announced again while its old entity exists, so
+/// devices with migrated IDs are announced again after the removal.
+pub fn cleanup(topics: &Topics, config: &config::Config, announced: &Announced) -> Vec<Outgoing> {
+    let mut updates = Vec::new();
+    let migrated = config
+        .devices
+        .iter()
+        .filter(|(_, device)| device.is_migrated())
+        .filter(|(_, device)| topics.device_identifier(device.addr) != announced.identifier);
+
+    let device = config
+        .devices
+        .iter()
+        .find(|(_, device)| topics.device_identifier(device.addr) == announced.identifier);
+    let Some((name, device)) = device else {
+        info!("Removing device {} from HA", announced.identifier);
+        updates.push(Outgoing::DiscoveryRemoved(announced.identifier.clone()));
+        for (name, device) in migrated {
+            updates.push(Outgoing::DiscoveryDevice(new_device(topics, name, device)));
+        }
+        return updates;
+    };
+
+    let mut discovery = new_device(topics, name, device);
+    let mut stale = 0;
+    for (id, platform) in &announced.components {
+        if discovery.components.contains_key(id) {
+            continue;
+        }
+        info!("Removing component {} of device {} from HA", id, name);
+        let component = Component {
+            platform: platform.clone(),
+            ..Default::default()
+        };
+        discovery.components.insert(id.clone(), component);
+        stale += 1;
+    }
+
+    if stale > 0 {
+        updates.push(Outgoing::DiscoveryDevice(discovery));
+        for (name, device) in migrated {
+            updates.push(Outgoing::DiscoveryDevice(new_device(topics, name, device)));
+        }
+    }
+    updates
+}
+
+/// Discovery updates turning what was announced for the `old` config into
+/// the `new` one. Unchanged devices are skipped; removals go first, so
+/// migrated unique IDs are free when announced again.
+pub fn diff(topics: &Topics, old: &config::Config, new: &config::Config) -> Vec<Outgoing> {
+    let announced: HashMap<String, Discovery> = old
+        .devices
+        .iter()
+        .map(|(name, device)| {
+            let identifier = topics.device_identifier(device.addr);
+            (identifier, new_device(topics, name, device))
+        })
+        .collect();
+    let identifiers: HashSet<String> = new
+        .devices
+        .values()
+        .map(|device| topics.device_identifier(device.addr))
+        .collect();
+
+    let mut removals = Vec::new();
+    let mut updates = Vec::new();
+    for identifier in announced.keys() {
+        if !identifiers.contains(identifier) {
+            info!("Removing device {} from HA", identifier);
+            removals.push(Outgoing::DiscoveryRemoved(identifier.clone()));
+        }
+    }
+
+    for (name, device) in &new.devices {
+        let mut discovery = new_device(topics, name, device);
+        let Some(old) = announced.get(&topics.device_identifier(device.addr)) else {
+            info!("Adding device {} to HA", name);
+            updates.push(Outgoing::DiscoveryDevice(discovery));
+            continue;
+        };
+        // Compare as JSON values, map order is not stable.
+        if serde_json::to_value(old).ok() == serde_json::to_value(&discovery).ok() {
+            continue;
+        }
+
+        info!("Updating device {} in HA", name);
+        let mut stale = 0;
+        for (id, component) in &old.components {
+            if !discovery.components.contains_key(id) {
+                let component = Component {
+                    platform: component.platform.clone(),
+                    ..Default::default()
+                };
+                discovery.components.insert(id.clone(), component);
+                stale += 1;
+            }
+        }
+        if stale > 0 {
+            removals.push(Outgoing::DiscoveryDevice(discovery));
+        } else {
+            updates.push(Outgoing::DiscoveryDevice(discovery));
+        }
+    }
+
+    removals.extend(updates);
+    removals
+}
+
+/// Command topics of all components, to subscribe to.
+pub fn command_topics(topics: &Topics, config: &config::Config) -> HashSet<String> {
+    config
+        .devices
+        .iter()
+        .flat_map(|(name, device)| new_device(topics, name, device).components.into_values())
+        .filter_map(|component| component.command_topic)
+        .collect()
+}
+
+/// Entity of an IO, for its unique ID.
+#[derive(Debug, Clone, Copy)]
+enum IOEntity {
+    Output,
+    Input,
+    Event,
+    Button(Trigger),
+}
+
+/// Unique ID of an IO entity. Explicit from config or derived from the
+/// (possibly migrated) device address and IO index.
+fn io_unique_id(
+    topics: &Topics,
+    device: &config::DeviceConfig,
+    io: &config::IOConfig,
+    entity: IOEntity,
+) -> String {
+    if let Some(unique_id) = &io.unique_id {
+        return match entity {
+            IOEntity::Output | IOEntity::Input => unique_id.clone(),
+            IOEntity::Event => format!("{}-event", unique_id),
+            IOEntity::Button(trigger) => format!("{}-{}", unique_id, trigger.name()),
+        };
+    }
+    let (addr, idx) = device.id_location(io);
+    topics.unique_id(&match entity {
+        IOEntity::Output => format!("{}-{}", addr, idx),
+        IOEntity::Input => format!("in-{}-{}", addr, idx),
+        IOEntity::Event => format!("ev-{}-{}", addr, idx),
+        IOEntity::Button(trigger) => format!("trig-{}-{}-{}", addr, idx, trigger.name()),
+    })
+}
+
+/// Unique ID of a device entity, derived from the (possibly migrated) device
+/// address. Like `sh-<addr>-<idx>`.
+fn device_unique_id(
+    topics: &Topics,
+    device: &config::DeviceConfig,
+    kind: &str,
+    idx: impl std::fmt::Display,
+) -> String {
+    topics.unique_id(&format!("{}-{}-{}", kind, device.id_addr(), idx))
+}
+
+/// Unique ID of a shutter calibration button.
+fn calibration_unique_id(
+    topics: &Topics,
+    device: &config::DeviceConfig,
+    shutter: u8,
+    action: &str,
+) -> String {
+    let idx = format!("{}-{}", shutter, action.to_lowercase());
+    device_unique_id(topics, device, "cal", idx)
+}
+
+/// Calibration buttons of each shutter.
+const CALIBRATION_ACTIONS: [&str; 3] = ["START", "MARK", "CANCEL"];
+
+/// Fields of the device Status message exposed as sensors.
+const STATUS_FIELDS: [&str; 3] = ["uptime", "errors", "warnings"];
+
+/// Unique IDs of all entities announced for a device, with the config
+/// section and label they come from. Status sensors have an empty section
+/// and the field as the label.
+pub fn unique_ids<'a>(
+    topics: &Topics,
+    device: &'a config::DeviceConfig,
+) -> Vec<(String, &'a str, &'a str)> {
+    let mut ids = Vec::new();
+    for (label, io) in &device.outputs {
+        if !device.is_shutter_output(io.id) {
+            let id = io_unique_id(topics, device, io, IOEntity::Output);
+            ids.push((id, "outputs", label.as_str()));
+        }
+    }
+    for (label, io) in &device.inputs {
+        let entities = [
+            IOEntity::Input,
+            IOEntity::Event,
+            IOEntity::Button(Trigger::ShortClick),
+            IOEntity::Button(Trigger::LongClick),
+        ];
+        for entity in entities {
+            let id = io_unique_id(topics, device, io, entity);
+            ids.push((id, "inputs", label.as_str()));
+        }
+    }
+    for (label, shutter) in &device.shutters {
+        let id = device_unique_id(topics, device, "sh", shutter.id);
+        ids.push((id, "shutters", label.as_str()));
+        for action in CALIBRATION_ACTIONS {
+            let id = calibration_unique_id(topics, device, shutter.id, action);
+            ids.push((id, "shutters", label.as_str()));
+        }
+    }
+    for (label, proc_id) in &device.procedures {
+        let id = device_unique_id(topics, device, "proc", proc_id);
+        ids.push((id, "procedures", label.as_str()));
+    }
+    for field in STATUS_FIELDS {
+        ids.push((device_unique_id(topics, device, "status", field), "", field));
+    }
+    ids
+}
+
+fn origin() -> Origin {
+    Origin {
         name: consts::GATE_NAME.to_string(),
         sw_version: consts::GATE_VERSION.to_string(),
         support_url: consts::GATE_URL.to_string(),
+    }
+}
+
+/// The gate itself, with bridge diagnostics. Boards are linked to it.
+pub fn new_gate(topics: &Topics) -> Discovery {
+    let device_id = DeviceId {
+        name: topics.node_id.clone(),
+        identifiers: vec![topics.gate_identifier()],
+        manufacturer: "smartenough".to_string(),
+        sw_version: Some(consts::GATE_VERSION.to_string()),
+        suggested_area: None,
+        via_device: None,
     };
 
+    let mut components = HashMap::new();
+    let connected = Component {
+        name: Some("connected".to_string()),
+        platform: "binary_sensor".to_string(),
+        device_class: Some("connectivity".to_string()),
+        unique_id: Some(topics.unique_id("gate-connected")),
+        entity_category: Some("diagnostic".to_string()),
+        state_topic: Some(topics.gate_status()),
+        payload_on: Some("online".to_string()),
+        payload_off: Some("offline".to_string()),
+        ..Default::default()
+    };
+    components.insert("connected".to_string(), connected);
+
+    let port = Component::new_gate_sensor(topics, "bridge port", "port");
+    components.insert("port".to_string(), port);
+    for (name, field) in [("frames received", "received"), ("frames sent", "sent")] {
+        let mut component = Component::new_gate_sensor(topics, name, field);
+        component.unit_of_measurement = Some("frames/min".to_string());
+        component.state_class = Some("measurement".to_string());
+        components.insert(field.to_string(), component);
+    }
+    let mut errors = Component::new_gate_sensor(topics, "errors", "errors");
+    errors.state_class = Some("total_increasing".to_string());
+    components.insert("errors".to_string(), errors);
+    for (name, field) in [
+        ("CAN RX queue", "rx_queue"),
+        ("CAN TX queue", "tx_queue"),
+        ("MQTT queue", "mqtt_queue"),
+    ] {
+        let mut component = Component::new_gate_sensor(topics, name, field);
+        component.state_class = Some("measurement".to_string());
+        components.insert(field.to_string(), component);
+    }
+    let mut time = Component::new_gate_sensor(topics, "last time broadcast", "last_time_broadcast");
+    time.device_class = Some("timestamp".to_string());
+    components.insert("last_time_broadcast".to_string(), time);
+
+    Discovery {
+        origin: origin(),
+        device: device_id,
+        components,
+    }
+}
+
+pub fn new_device(topics: &Topics, name: &str, config: &config::DeviceConfig) -> Discovery {
     let device_id = DeviceId {
-        name: name.to_string(),
-        identifiers: vec![format!("gate-{}", config.addr)],
+        name: config.name.clone().unwrap_or_else(|| name.to_string()),
+        identifiers: vec![topics.device_identifier(config.addr)],
         manufacturer: "smartenough".to_string(),
+        sw_version: None,
+        suggested_area: config.area.clone(),
+        via_device: Some(topics.gate_identifier()),
     };
 
     let mut components = HashMap::new();
@@ -128,8 +816,31 @@ pub fn new_device(name: &str, config: &config::DeviceConfig) -> Discovery {
         unique_label.insert(label.clone());
         unique_id.insert(io.id);
 
+        if config.is_shutter_output(io.id) {
+            warn!(
+                "Output {} of device {:?} drives a shutter, not exposing it as a switch",
+                label, name
+            );
+            continue;
+        }
+
+        let kind = match OutputKind::from_config(io.io_type.as_deref()) {
+            Some(kind) => kind,
+            None => {
+                error!(
+                    "Unknown output type {:?} of {} in device {:?}, using switch",
+                    io.io_type, label, name
+                );
+                OutputKind::Switch
+            }
+        };
+
         // Create device components.
-        let component = Component::new_switch(&label, config.addr, io.id, None);
+        let display_name = io.display_name(label);
+        let entity_id = io_unique_id(topics, config, io, IOEntity::Output);
+        let mut component =
+            Component::new_output(topics, display_name, entity_id, config.addr, io.id, kind);
+        component.set_metadata(io, label, name);
         components.insert(label.clone(), component);
     }
 
@@ -138,7 +849,10 @@ pub fn new_device(name: &str, config: &config::DeviceConfig) -> Discovery {
 
     for (label, io) in config.inputs.iter() {
         if unique_label.contains(label) {
-            error!("Duplicated input/output label {} in device {:?}", label, name);
+            error!(
+                "Duplicated input/output label {} in device {:?}",
+                label, name
+            );
             continue;
         }
 
@@ -147,13 +861,127 @@ pub fn new_device(name: &str, config: &config::DeviceConfig) -> Discovery {
             continue;
         }
 
+        unique_label.insert(label.clone());
+        unique_id.insert(io.id);
+
+        let device_class = match io.io_type.as_deref() {
+            Some(class) if !INPUT_CLASSES.contains(&class) => {
+                error!(
+                    "Unknown input type {:?} of {} in device {:?}, ignoring",
+                    class, label, name
+                );
+                None
+            }
+            class => class.map(str::to_string),
+        };
+
         // Create device components.
-        let component = Component::new_input(&label, config.addr, io.id, None);
+        let display_name = io.display_name(label);
+        let entity_id = io_unique_id(topics, config, io, IOEntity::Input);
+        let mut component = Component::new_input(
+            topics,
+            display_name,
+            entity_id,
+            config.addr,
+            io.id,
+            device_class,
+        );
+        component.set_metadata(io, label, name);
+        components.insert(label.clone(), component);
+
+        // Button-like usage: event entity and device triggers for automations.
+        let entity_id = io_unique_id(topics, config, io, IOEntity::Event);
+        let component =
+            Component::new_input_event(topics, display_name, entity_id, config.addr, io.id);
+        components.insert(format!("{}-event", label), component);
+        for trigger in Trigger::ALL {
+            let component =
+                Component::new_input_trigger(topics, display_name, config.addr, io.id, trigger);
+            components.insert(format!("{}-{}", label, trigger.name()), component);
+        }
+
+        // Simulate clicks. Any trigger can be sent to the topic by a service call.
+        for trigger in [Trigger::ShortClick, Trigger::LongClick] {
+            let entity_id = io_unique_id(topics, config, io, IOEntity::Button(trigger));
+            let component = Component::new_input_button(
+                topics,
+                display_name,
+                entity_id,
+                config.addr,
+                io.id,
+                trigger,
+            );
+            components.insert(format!("{}-{}-button", label, trigger.name()), component);
+        }
+    }
+
+    unique_id.clear();
+
+    for (label, shutter) in config.shutters.iter() {
+        if unique_label.contains(label) {
+            error!("Duplicated shutter label {} in device {:?}", label, name);
+            continue;
+        }
+
+        if unique_id.contains(&shutter.id) {
+            error!("Duplicated shutter id {} in device {:?}", shutter.id, name);
+            continue;
+        }
+
+        unique_label.insert(label.clone());
+        unique_id.insert(shutter.id);
+
+        let entity_id = device_unique_id(topics, config, "sh", shutter.id);
+        let component = Component::new_cover(topics, label, entity_id, config.addr, shutter.id);
         components.insert(label.clone(), component);
+
+        for action in CALIBRATION_ACTIONS {
+            let entity_id = calibration_unique_id(topics, config, shutter.id, action);
+            let component = Component::new_calibration_button(
+                topics,
+                label,
+                entity_id,
+                config.addr,
+                shutter.id,
+                action,
+            );
+            components.insert(
+                format!("{}-calibration-{}", label, action.to_lowercase()),
+                component,
+            );
+        }
+    }
+
+    unique_id.clear();
+
+    for (label, proc_id) in config.procedures.iter() {
+        if unique_label.contains(label) {
+            error!("Duplicated procedure label {} in device {:?}", label, name);
+            continue;
+        }
+
+        if unique_id.contains(proc_id) {
+            error!("Duplicated procedure id {} in device {:?}", proc_id, name);
+            continue;
+        }
+
+        unique_label.insert(label.clone());
+        unique_id.insert(*proc_id);
+
+        let entity_id = device_unique_id(topics, config, "proc", *proc_id);
+        let component =
+            Component::new_procedure_button(topics, label, entity_id, config.addr, *proc_id);
+        components.insert(label.clone(), component);
+    }
+
+    for field in STATUS_FIELDS {
+        let entity_id = device_unique_id(topics, config, "status", field);
+        let component = Component::new_status_sensor(topics, entity_id, config.addr, field);
+        components.insert(format!("status-{}", field), component);
     }
 
     Discovery {
-        origin,
+        origin: origin(),
         device: device_id,
         components,
     }
@@ -1,36 +1,194 @@
 use super::discovery;
+use crate::consts::Trigger;
+use crate::message::{args::OutputChangeRequest, Message, MessageRaw};
+use crate::shutters::{self, Direction, Position, TargetPosition};
+use serde::Serialize;
+use std::time::Duration;
+
+/// Gate diagnostics.
+#[derive(Debug, Clone, Serialize)]
+pub struct GateStats {
+    /// Serial port of the CAN bridge.
+    pub port: String,
+    /// CAN frames per minute.
+    pub received: u64,
+    pub sent: u64,
+    /// Framing and parse errors since start.
+    pub errors: u64,
+    /// Frames waiting in the bridge queues.
+    pub rx_queue: usize,
+    pub tx_queue: usize,
+    /// Messages waiting to be published.
+    pub mqtt_queue: usize,
+    /// RFC 3339 time of the last time broadcast.
+    pub last_time_broadcast: Option<String>,
+}
 
 /// Things we sent to HA.
 #[derive(Debug)]
 pub enum Outgoing {
     /// Subscribe to a new topic given as argument. Not a real message.
     Subscribe(String),
+    /// Unsubscribe from a topic no longer used. Not a real message.
+    Unsubscribe(String),
     /// Send on initialization once.
     Initial,
-    /// Debugging only
-    RawTest(Vec<u8>),
+    /// Frame received from the bus, for the raw channel.
+    RawFrame(MessageRaw),
     /// Discovery message, to be sent to
     /// <discovery_prefix>/<component>/[<node_id>/]<object_id>/config
     /// Usually:
-    /// homeassistant/device/io-gate-[devaddr]/config
+    /// homeassistant/device/gate-[devaddr]/config
     DiscoveryDevice(discovery::Discovery),
+    /// Remove the device with the given identifier from HA.
+    DiscoveryRemoved(String),
+
+    /// Device reports the output was changed. None if the state is unknown.
+    OutputChanged {
+        device: u8,
+        output: u8,
+        on: Option<bool>,
+    },
+
+    /// Device reports the input state (active or not). None if unknown.
+    InputChanged {
+        device: u8,
+        input: u8,
+        on: Option<bool>,
+    },
+
+    /// Message decoded from the bus, for the JSON API.
+    ApiEvent { device: u8, message: Message },
+
+    /// Periodic gate diagnostics
+    GateStats(GateStats),
+
+    /// Periodic device status
+    DeviceStatus {
+        device: u8,
+        /// Seconds since the device start.
+        uptime: u32,
+        errors: u16,
+        warnings: u16,
+    },
+
+    /// Estimated shutter position changed
+    ShutterChanged {
+        device: u8,
+        shutter: u8,
+        /// Current movement, if moving.
+        direction: Option<Direction>,
+        position: Position,
+    },
+
+    /// Device reports an input trigger (click, long click, ...)
+    InputTriggered {
+        device: u8,
+        input: u8,
+        trigger: Trigger,
+    },
+}
+
+/// Cover command from HA. Positions use the shutter convention: 0 is open,
+/// 100 is closed (HA uses the opposite).
+#[derive(Debug, Clone, Copy)]
+pub enum CoverCommand {
+    Open,
+    Close,
+    Stop,
+    /// Go to a given height.
+    Position(u8),
+    /// Keep height, change tilt.
+    Tilt(u8),
+    /// Shutter driver command from the JSON API, sent as is.
+    Driver(shutters::Cmd),
+}
+
+impl CoverCommand {
+    /// Driver command, given the estimated current position.
+    pub fn to_cmd(self, position: Position) -> shutters::Cmd {
+        match self {
+            Self::Open => shutters::Cmd::Open,
+            Self::Close => shutters::Cmd::Close,
+            // Firmware has no stop; ask it to stay where we think it is.
+            Self::Stop => shutters::Cmd::Go(TargetPosition::new(
+                position.height.percent(),
+                position.tilt.percent(),
+            )),
+            Self::Position(height) => {
+                shutters::Cmd::Go(TargetPosition::new(height, position.tilt.percent()))
+            }
+            Self::Tilt(tilt) => shutters::Cmd::Tilt(tilt),
+            Self::Driver(cmd) => cmd,
+        }
+    }
+}
 
-    /// Device reports the output was changed
-    OutputChanged { device: u8, output: u8, on: bool },
+/// Step of the shutter calibration requested from HA.
+#[derive(Debug, Clone, Copy)]
+pub enum CalibrationCommand {
+    Start,
+    Mark,
+    Cancel,
 }
 
 /// Things HA sents to us (like: trigger switch)
 #[derive(Debug)]
 pub enum Incoming {
-    RawTest(Vec<u8>),
+    /// HA (re)started and needs discovery and state again.
+    HomeAssistantOnline,
+
+    /// Command received on the JSON API, to be sent to the device.
+    Api { device: u8, message: Message },
+
+    /// Retained discovery of our device, published in the past.
+    Announced(discovery::Announced),
+
+    /// Frame to inject to the bus as is.
+    RawFrame(MessageRaw),
 
-    /// Set output on a device to given state (on or off)
+    /// Set output on a device to given state (on, off or toggle)
     SetOutput {
         /// Device address
         device: u8,
         /// Output index
         output: u8,
-        /// On or off.
-        on: bool,
+        state: OutputChangeRequest,
+        /// Revert the change after this time. Timed by the gate.
+        duration: Option<Duration>,
+    },
+
+    /// Control a shutter on a device.
+    Shutter {
+        /// Device address
+        device: u8,
+        /// Shutter index
+        shutter: u8,
+        cmd: CoverCommand,
+    },
+
+    /// Simulate an input trigger on a device.
+    TriggerInput {
+        /// Device address
+        device: u8,
+        /// Input index
+        input: u8,
+        trigger: Trigger,
+    },
+
+    /// Call a procedure programmed in the device VM.
+    CallProcedure {
+        /// Device address
+        device: u8,
+        proc_id: u8,
+    },
+
+    /// Drive the shutter calibration procedure.
+    ShutterCalibration {
+        /// Device address
+        device: u8,
+        /// Shutter index
+        shutter: u8,
+        cmd: CalibrationCommand,
     },
 }
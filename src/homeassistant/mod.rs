@@ -1,6 +1,9 @@
 mod connection;
 pub mod discovery;
 mod message;
+pub mod reload;
+mod topics;
 
-pub use connection::{HomeAssistant, Initiator};
-pub use message::{Incoming, Outgoing};
+pub use connection::{Broker, HomeAssistant, Initiator};
+pub use message::{CalibrationCommand, CoverCommand, GateStats, Incoming, Outgoing};
+pub use topics::Topics;
@@ -0,0 +1,114 @@
+//! Config changes applied while running.
+use super::{discovery, HomeAssistant, Outgoing};
+use crate::config::Config;
+use crate::message::{Message, MessageRaw};
+use crate::shutters::SharedTrackers;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+use tracing::{error, info, warn};
+
+/// Apply a changed config without restarting: subscribe new command topics
+/// (and drop removed ones), announce changed devices only and configure
+/// changed shutters.
+pub async fn apply_config(
+    old: &Config,
+    new: &Config,
+    ha: &HomeAssistant,
+    comm_tx: &mpsc::Sender<MessageRaw>,
+    trackers: &SharedTrackers,
+) -> anyhow::Result<()> {
+    let subscribed = discovery::command_topics(ha.topics(), old);
+    let command_topics = discovery::command_topics(ha.topics(), new);
+    for topic in command_topics.difference(&subscribed) {
+        ha.send(Outgoing::Subscribe(topic.clone())).await?;
+    }
+    // Commands of removed entities would still be carried out.
+    for topic in subscribed.difference(&command_topics) {
+        ha.send(Outgoing::Unsubscribe(topic.clone())).await?;
+    }
+    for update in discovery::diff(ha.topics(), old, new) {
+        ha.send(update).await?;
+    }
+
+    trackers.lock().unwrap().reconfigure(new);
+    for device in new.devices.values() {
+        for shutter in device.shutters.values() {
+            let commands = shutter.commands();
+            let unchanged = old
+                .shutter(device.addr, shutter.id)
+                .is_some_and(|old| old.commands() == commands);
+            if unchanged {
+                continue;
+            }
+            for cmd in commands {
+                let msg = Message::ShutterCmd {
+                    shutter_idx: shutter.id,
+                    cmd,
+                };
+                comm_tx.send(msg.to_raw(device.addr)).await?;
+            }
+        }
+
+        // New devices tell us their state.
+        if !old.devices.values().any(|old| old.addr == device.addr) {
+            comm_tx
+                .send(Message::RequestStatus.to_raw(device.addr))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Modification time of the file, if readable.
+fn modified(path: &str) -> Option<std::time::SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// Reload the config when the file changes or on SIGHUP. An invalid config
+/// is reported and the running one is kept.
+pub async fn watch_config(
+    path: String,
+    interval: u64,
+    config: watch::Sender<Arc<Config>>,
+    ha: Arc<HomeAssistant>,
+    comm_tx: Arc<mpsc::Sender<MessageRaw>>,
+    trackers: SharedTrackers,
+) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut poll = tokio::time::interval(std::time::Duration::from_secs(interval.max(1)));
+    let mut last_modified = modified(&path);
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                info!("SIGHUP received, reloading config");
+            }
+            _ = poll.tick(), if interval > 0 => {
+                if modified(&path) == last_modified {
+                    continue;
+                }
+                info!("Config file {} changed, reloading", path);
+            }
+        }
+        last_modified = modified(&path);
+
+        let new = match Config::from_file_checked(&path) {
+            Ok(new) => new,
+            Err(err) => {
+                error!("Keeping the running config: {:?}", err);
+                continue;
+            }
+        };
+        let old = config.borrow().clone();
+        if old.gate != new.gate {
+            warn!("Gate settings changed, restart to apply them");
+        }
+        if let Err(err) = apply_config(&old, &new, &ha, &comm_tx, &trackers).await {
+            error!("Unable to apply the config, SIGHUP retries: {:?}", err);
+            continue;
+        }
+        config.send_replace(Arc::new(new));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::consts;
+
+/// MQTT topic layout and identifiers of a gate instance. Multiple gates can
+/// share a broker as long as their node IDs differ.
+#[derive(Debug, Clone)]
+pub struct Topics {
+    /// HA discovery prefix.
+    pub discovery: String,
+    /// Prefix of state and command topics.
+    pub control: String,
+    /// Identifies this gate in topics, unique IDs and the MQTT client ID.
+    pub node_id: String,
+}
+
+impl Default for Topics {
+    fn default() -> Self {
+        Self::new(
+            consts::HA_DISCOVERY_TOPIC,
+            consts::HA_CONTROL_TOPIC,
+            consts::GATE_NAME,
+        )
+    }
+}
+
+impl Topics {
+    pub fn new(discovery: &str, control: &str, node_id: &str) -> Self {
+        Self {
+            discovery: discovery.to_string(),
+            control: control.to_string(),
+            node_id: node_id.to_string(),
+        }
+    }
+
+    /// Base of all topics of this gate: `<control>/<node_id>`, or just
+    /// `<control>` with the default node ID.
+    pub fn base(&self) -> String {
+        if self.node_id == consts::GATE_NAME {
+            // Kept from before node IDs were configurable, so HA automations
+            // on the topics keep working.
+            self.control.clone()
+        } else {
+            format!("{}/{}", self.control, self.node_id)
+        }
+    }
+
+    /// Unique ID of an entity: `<node_id>-<suffix>`
+    pub fn unique_id(&self, suffix: &str) -> String {
+        format!("{}-{}", self.node_id, suffix)
+    }
+
+    /// HA device identifier of a board.
+    pub fn device_identifier(&self, device_addr: u8) -> String {
+        if self.node_id == consts::GATE_NAME {
+            // Kept from before node IDs were configurable, so HA keeps devices.
+            format!("gate-{}", device_addr)
+        } else {
+            format!("{}-gate-{}", self.node_id, device_addr)
+        }
+    }
+
+    /// HA device identifier of the gate itself.
+    pub fn gate_identifier(&self) -> String {
+        self.node_id.clone()
+    }
+
+    /// Board address encoded in a device identifier of this gate.
+    pub fn device_addr(&self, identifier: &str) -> Option<u8> {
+        let addr = if self.node_id == consts::GATE_NAME {
+            identifier.strip_prefix("gate-")?
+        } else {
+            identifier
+                .strip_prefix(self.node_id.as_str())?
+                .strip_prefix("-gate-")?
+        };
+        addr.parse().ok()
+    }
+
+    /// MQTT client ID of the gate.
+    pub fn client_id(&self) -> String {
+        format!("{}-mqtt", self.node_id)
+    }
+
+    /// Device discovery config topic:
+    /// `<discovery_prefix>/device/<identifier>/config`
+    pub fn discovery_config(&self, identifier: &str) -> String {
+        format!("{}/device/{}/config", self.discovery, identifier)
+    }
+
+    /// Identifier of the device whose discovery config is on the topic.
+    pub fn discovery_identifier<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        topic
+            .strip_prefix(self.discovery.as_str())?
+            .strip_prefix("/device/")?
+            .strip_suffix("/config")
+    }
+
+    /// Topic on which HA announces its birth (`online`) and death (`offline`)
+    pub fn ha_status(&self) -> String {
+        format!("{}/status", self.discovery)
+    }
+
+    /// Availability of the gate daemon itself: `online` or `offline`.
+    pub fn gate_status(&self) -> String {
+        format!("{}/status", self.base())
+    }
+
+    /// Gate diagnostics published periodically as JSON.
+    pub fn gate_stats(&self) -> String {
+        format!("{}/stats", self.base())
+    }
+
+    /// JSON API: every message decoded from the bus.
+    pub fn api_events(&self) -> String {
+        format!("{}/api/events", self.base())
+    }
+
+    /// JSON API: commands encoded to the bus.
+    pub fn api_command(&self) -> String {
+        format!("{}/api/command", self.base())
+    }
+
+    /// Raw channel: every frame received from the bus.
+    pub fn raw_rx(&self) -> String {
+        format!("{}/raw/rx", self.base())
+    }
+
+    /// Raw channel: frames injected to the bus. Should be restricted by the
+    /// broker ACL.
+    pub fn raw_inject(&self) -> String {
+        format!("{}/raw/inject", self.base())
+    }
+
+    /// Base of topics of a single board.
+    fn device(&self, device_addr: u8) -> String {
+        format!("{}/{}", self.base(), device_addr)
+    }
+
+    /// Topic on which HA requests output changes (ON/OFF)
+    pub fn output_command(&self, device_addr: u8, idx: u8) -> String {
+        format!("{}/switch/{}/set", self.device(device_addr), idx)
+    }
+
+    /// Topic on which output state (ON/OFF) is published
+    pub fn output_state(&self, device_addr: u8, idx: u8) -> String {
+        format!("{}/switch/{}/state", self.device(device_addr), idx)
+    }
+
+    /// Topic on which input state (ON/OFF) is published
+    pub fn input_state(&self, device_addr: u8, idx: u8) -> String {
+        format!("{}/input/{}/state", self.device(device_addr), idx)
+    }
+
+    /// Topic on which input triggers are published as `{"event_type": ...}`
+    pub fn input_event(&self, device_addr: u8, idx: u8) -> String {
+        format!("{}/input/{}/event", self.device(device_addr), idx)
+    }
+
+    /// Topic accepting trigger names to simulate on the input
+    pub fn input_trigger(&self, device_addr: u8, idx: u8) -> String {
+        format!("{}/input/{}/trigger", self.device(device_addr), idx)
+    }
+
+    /// Shutter topics: `set` (OPEN/CLOSE/STOP), `position/set`, `tilt/set`,
+    /// `state` and `calibrate`.
+    pub fn shutter(&self, device_addr: u8, idx: u8, suffix: &str) -> String {
+        format!("{}/shutter/{}/{}", self.device(device_addr), idx, suffix)
+    }
+
+    /// Topic on which HA calls device procedures
+    pub fn procedure(&self, device_addr: u8, proc_id: u8) -> String {
+        format!("{}/procedure/{}/call", self.device(device_addr), proc_id)
+    }
+
+    /// Topic on which device Status (uptime, errors, warnings) is published as JSON
+    pub fn device_status(&self, device_addr: u8) -> String {
+        format!("{}/status", self.device(device_addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_node_keeps_legacy_topics() {
+        let topics = Topics::default();
+        assert_eq!(topics.output_command(3, 1), "smartenough/3/switch/1/set");
+        assert_eq!(topics.gate_status(), "smartenough/status");
+        assert_eq!(topics.device_identifier(3), "gate-3");
+        assert_eq!(topics.device_addr("gate-3"), Some(3));
+    }
+
+    #[test]
+    fn node_id_in_topics() {
+        let topics = Topics::new("homeassistant", "smartenough", "attic");
+        assert_eq!(
+            topics.output_command(3, 1),
+            "smartenough/attic/3/switch/1/set"
+        );
+        assert_eq!(topics.gate_status(), "smartenough/attic/status");
+        assert_eq!(topics.device_identifier(3), "attic-gate-3");
+        assert_eq!(topics.device_addr("attic-gate-3"), Some(3));
+        assert_eq!(topics.device_addr("gate-3"), None);
+    }
+}
@@ -1,6 +1,9 @@
+pub mod api;
 pub mod comm;
 pub mod config;
 pub mod consts;
-pub mod shutters;
 pub mod homeassistant;
 pub mod message;
+pub mod scan;
+pub mod shutters;
+pub mod state;
@@ -1,42 +1,200 @@
 use anyhow::Context;
 use chrono::{Datelike, Timelike};
-use clap::Parser;
+use clap::builder::BoolishValueParser;
+use clap::{ArgAction, Parser, Subcommand};
 use io_gate::comm;
-use io_gate::config::Config;
-use io_gate::homeassistant::{self, discovery, HomeAssistant};
+use io_gate::config::{self, Config, GateConfig, Secret, Settings};
+use io_gate::homeassistant::{
+    self, discovery, reload, CalibrationCommand, CoverCommand, HomeAssistant,
+};
 use io_gate::message::{
-    Message, BROADCAST_ADDRESS,
-    args::{InfoCode, OutputChangeRequest, IOType}
+    args::{IOType, InfoCode, OutputChangeRequest, Trigger},
+    Message, MessageRaw, BROADCAST_ADDRESS,
 };
-use std::sync::Arc;
-use tracing::{error, info};
+use io_gate::scan::{self, Scan};
+use io_gate::shutters::{self, SharedTrackers};
+use io_gate::state::StateCache;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+use tracing::{error, info, warn};
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::{fmt, EnvFilter};
 
+// Daemon settings can also be given in the `gate:` section of the config
+// file. Command line and environment take precedence; switches take an
+// optional value to override the config, eg. `--mqtt-tls=false`.
 #[derive(Parser, Debug)]
 struct Args {
-    #[arg(long, default_value = "config.yaml")]
+    #[arg(long, env = "IO_GATE_CONFIG_PATH", default_value = "config.yaml")]
     config_path: String,
 
     // USB Connection
-    #[arg(long, default_value = "/dev/ttyACM0")]
-    port_name: String,
-    #[arg(long, default_value_t = 115200)]
-    baud_rate: u32,
+    /// Serial port of the CAN bridge [default: /dev/ttyACM0]
+    #[arg(long, env = "IO_GATE_PORT_NAME")]
+    port_name: Option<String>,
+    /// [default: 115200]
+    #[arg(long, env = "IO_GATE_BAUD_RATE")]
+    baud_rate: Option<u32>,
 
     // MQTT connection
-    #[arg(long)]
-    mqtt_host: String,
-    #[arg(long, default_value_t = 1883)]
-    mqtt_port: u16,
-    #[arg(long, default_value = "")]
-    mqtt_username: String,
-    #[arg(long, default_value = "")]
-    mqtt_password: String,
+    #[arg(long, env = "IO_GATE_MQTT_HOST")]
+    mqtt_host: Option<String>,
+    /// [default: 1883]
+    #[arg(long, env = "IO_GATE_MQTT_PORT")]
+    mqtt_port: Option<u16>,
+    #[arg(long, env = "IO_GATE_MQTT_USERNAME")]
+    mqtt_username: Option<String>,
+    #[arg(long, env = "IO_GATE_MQTT_PASSWORD", hide_env_values = true)]
+    mqtt_password: Option<Secret>,
+    /// File containing the MQTT password, like a docker or systemd secret
+    #[arg(
+        long,
+        env = "IO_GATE_MQTT_PASSWORD_FILE",
+        conflicts_with = "mqtt_password"
+    )]
+    mqtt_password_file: Option<String>,
+    /// Connect to the broker using TLS (usually on port 8883)
+    #[arg(
+        long,
+        env = "IO_GATE_MQTT_TLS",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = BoolishValueParser::new()
+    )]
+    mqtt_tls: Option<bool>,
+    /// CA certificate (PEM) verifying the broker; system roots when not given
+    #[arg(long, env = "IO_GATE_MQTT_CA_FILE")]
+    mqtt_ca_file: Option<String>,
+    /// Client certificate (PEM) for mutual TLS authentication
+    #[arg(long, env = "IO_GATE_MQTT_CLIENT_CERT")]
+    mqtt_client_cert: Option<String>,
+    /// Private key (PEM) of the client certificate
+    #[arg(long, env = "IO_GATE_MQTT_CLIENT_KEY")]
+    mqtt_client_key: Option<String>,
+    /// Connect over WebSocket (wss:// when combined with --mqtt-tls)
+    #[arg(
+        long,
+        env = "IO_GATE_MQTT_WEBSOCKET",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = BoolishValueParser::new()
+    )]
+    mqtt_websocket: Option<bool>,
+    /// HTTP path of the broker WebSocket endpoint [default: /mqtt]
+    #[arg(long, env = "IO_GATE_MQTT_WS_PATH")]
+    mqtt_ws_path: Option<String>,
+
+    /// Prefix of HA discovery topics [default: homeassistant]
+    #[arg(long, env = "IO_GATE_DISCOVERY_PREFIX")]
+    discovery_prefix: Option<String>,
+    /// Prefix of state and command topics [default: smartenough]
+    #[arg(long, env = "IO_GATE_CONTROL_PREFIX")]
+    control_prefix: Option<String>,
+    /// Gate identity used in topics, unique IDs and the MQTT client ID. Must
+    /// differ between gates sharing a broker. Topics of the default gate are
+    /// directly under the control prefix. [default: io-gate]
+    #[arg(long, alias = "device-name", env = "IO_GATE_NODE_ID")]
+    node_id: Option<String>,
 
     // Other
-    #[arg(long, default_value = "io-gate")]
-    device_name: String,
+    /// Request status from all devices every N seconds (0 disables) [default: 0]
+    #[arg(long, env = "IO_GATE_STATUS_INTERVAL")]
+    status_interval: Option<u64>,
+    /// Check the config file for changes every N seconds (0 disables). The
+    /// config is also reloaded on SIGHUP. [default: 5]
+    #[arg(long, env = "IO_GATE_RELOAD_INTERVAL")]
+    reload_interval: Option<u64>,
+    /// Publish all bus messages as JSON and accept JSON commands under
+    /// `<control-prefix>[/<node-id>]/api/`
+    #[arg(
+        long,
+        env = "IO_GATE_JSON_API",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = BoolishValueParser::new()
+    )]
+    json_api: Option<bool>,
+    /// Publish every bus frame and accept frames for injection under
+    /// `<control-prefix>[/<node-id>]/raw/`. For debugging only - anyone able to
+    /// publish there controls the bus.
+    #[arg(
+        long,
+        env = "IO_GATE_RAW_BUS",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = BoolishValueParser::new()
+    )]
+    raw_bus: Option<bool>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Measure shutter travel times, store them in config and send them to
+    /// the device.
+    Calibrate {
+        /// Device name from config
+        device: String,
+        /// Shutter name from device config
+        shutter: String,
+        /// Do not modify the config file
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Validate the config file and report all problems found.
+    CheckConfig,
+    /// Probe all bus addresses and list devices that answer.
+    Scan {
+        /// Seconds to wait for late answers after probing
+        #[arg(long, default_value_t = 3)]
+        wait: u64,
+        /// Write a config skeleton of the found devices to a new file
+        #[arg(long)]
+        write: Option<String>,
+    },
+}
+
+impl Args {
+    /// Settings given on the command line or in the environment.
+    fn gate(&self) -> GateConfig {
+        GateConfig {
+            port_name: self.port_name.clone(),
+            baud_rate: self.baud_rate,
+            mqtt_host: self.mqtt_host.clone(),
+            mqtt_port: self.mqtt_port,
+            mqtt_username: self.mqtt_username.clone(),
+            mqtt_password: self.mqtt_password.clone(),
+            mqtt_password_file: self.mqtt_password_file.clone(),
+            mqtt_tls: self.mqtt_tls,
+            mqtt_ca_file: self.mqtt_ca_file.clone(),
+            mqtt_client_cert: self.mqtt_client_cert.clone(),
+            mqtt_client_key: self.mqtt_client_key.clone(),
+            mqtt_websocket: self.mqtt_websocket,
+            mqtt_ws_path: self.mqtt_ws_path.clone(),
+            discovery_prefix: self.discovery_prefix.clone(),
+            control_prefix: self.control_prefix.clone(),
+            node_id: self.node_id.clone(),
+            status_interval: self.status_interval,
+            reload_interval: self.reload_interval,
+            json_api: self.json_api,
+            raw_bus: self.raw_bus,
+        }
+    }
 }
 
 fn init_log() {
@@ -64,13 +222,19 @@ fn init_log() {
 
 /// Perform initial configuration and device discovery.
 async fn init_config(config: &Config, ha: &HomeAssistant) -> anyhow::Result<()> {
+    // Gate first, boards refer to it with via_device.
+    let gate = discovery::new_gate(ha.topics());
+    ha.send(homeassistant::Outgoing::DiscoveryDevice(gate))
+        .await?;
+
     for (device_name, cfg) in &config.devices {
-        let message = discovery::new_device(device_name, cfg);
+        let message = discovery::new_device(ha.topics(), device_name, cfg);
 
         // Subscribe to HomeAssistant state changes.
         for component in message.components.values() {
             if let Some(command_topic) = &component.command_topic {
-                ha.send(homeassistant::Outgoing::Subscribe(command_topic.clone())).await?;
+                ha.send(homeassistant::Outgoing::Subscribe(command_topic.clone()))
+                    .await?;
             }
         }
 
@@ -82,40 +246,412 @@ async fn init_config(config: &Config, ha: &HomeAssistant) -> anyhow::Result<()>
     Ok(())
 }
 
+/// Send output changes requested by the shutter calibration.
+async fn send_outputs(
+    comm_tx: &mpsc::Sender<MessageRaw>,
+    device: u8,
+    requests: shutters::OutputRequests,
+) -> anyhow::Result<()> {
+    for (output, on) in requests {
+        let msg = Message::SetOutput {
+            output,
+            state: OutputChangeRequest::from_bool(on),
+        };
+        comm_tx.send(msg.to_raw(device)).await?;
+    }
+    Ok(())
+}
+
+/// Probe all addresses on the bus and report the devices that answer.
+async fn scan(
+    settings: &Settings,
+    config: &Config,
+    wait: u64,
+    write: Option<&str>,
+) -> anyhow::Result<()> {
+    let mut comm = comm::run(settings.port_name.clone(), settings.baud_rate).await?;
+    let comm_tx = comm.tx.clone();
+    let probe = async move {
+        for addr in 0..=config::MAX_ADDRESS {
+            for msg in [Message::RequestStatus, Message::Ping { body: addr as u16 }] {
+                comm_tx.send(msg.to_raw(addr)).await?;
+            }
+            // Don't flood the bus.
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(wait)).await;
+        anyhow::Ok(())
+    };
+    tokio::pin!(probe);
+
+    println!("Scanning addresses 0-{}...", config::MAX_ADDRESS);
+    let mut found = Scan::default();
+    loop {
+        tokio::select! {
+            result = &mut probe => {
+                result?;
+                break;
+            }
+            raw = comm.rx.recv() => {
+                let raw = raw.context("CAN bridge disconnected")?;
+                let (addr, _) = raw.addr_type();
+                match Message::from_raw(&raw) {
+                    Some(msg) if addr <= config::MAX_ADDRESS => found.handle(addr, &msg),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    print!("{}", found.report(config));
+    if let Some(path) = write {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .with_context(|| format!("Unable to create {}", path))?;
+        std::io::Write::write_all(&mut file, found.skeleton().as_bytes())?;
+        println!("Config skeleton written to {}", path);
+    }
+    Ok(())
+}
+
+/// Interactive shutter calibration from the terminal.
+async fn calibrate(
+    args: &Args,
+    settings: &Settings,
+    config: &Config,
+    device: &str,
+    shutter: &str,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let device_config = config
+        .devices
+        .get(device)
+        .with_context(|| format!("No device {} in config", device))?;
+    let shutter_config = device_config
+        .shutters
+        .get(shutter)
+        .with_context(|| format!("No shutter {} in device {}", shutter, device))?;
+    let addr = device_config.addr;
+
+    let comm = comm::run(settings.port_name.clone(), settings.baud_rate).await?;
+    // We only drive outputs; ignore the bus traffic.
+    let mut comm_rx = comm.rx;
+    tokio::spawn(async move { while comm_rx.recv().await.is_some() {} });
+
+    let (mut calibration, requests) =
+        shutters::Calibration::start(shutter_config.down, shutter_config.up, Instant::now());
+    send_outputs(&comm.tx, addr, requests).await?;
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while calibration.step() != shutters::CalibrationStep::Done {
+        println!(
+            "{} Press Enter to mark, `c` + Enter to cancel.",
+            calibration.step().instructions()
+        );
+        let deadline = tokio::time::Instant::from_std(calibration.deadline());
+        let line = tokio::time::timeout_at(deadline, lines.next_line()).await;
+        let stopped = match line {
+            Err(_) => Some("timed out"),
+            Ok(Ok(Some(line))) if line.trim() != "c" => None,
+            Ok(_) => Some("cancelled"),
+        };
+        if let Some(reason) = stopped {
+            send_outputs(&comm.tx, addr, calibration.cancel()).await?;
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            anyhow::bail!("Calibration {}", reason);
+        }
+        let requests = calibration.mark(Instant::now());
+        send_outputs(&comm.tx, addr, requests).await?;
+    }
+
+    let timings = calibration.result().expect("Calibration is done");
+    println!(
+        "Measured: rise_time: {:.2} drop_time: {:.2} tilt_time: {:.2}",
+        timings.rise, timings.drop, timings.tilt
+    );
+    timings.check_measured().map_err(anyhow::Error::msg)?;
+    if !dry_run {
+        config::store_shutter_timings(&args.config_path, addr, shutter_config.id, &timings)?;
+        println!("Stored in {}", args.config_path);
+    }
+
+    for cmd in timings.commands(shutter_config.over_time()) {
+        let msg = Message::ShutterCmd {
+            shutter_idx: shutter_config.id,
+            cmd,
+        };
+        comm.tx.send(msg.to_raw(addr)).await?;
+    }
+    // Let the writer flush the queue.
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    Ok(())
+}
+
+type SharedState = Arc<Mutex<StateCache>>;
+type SharedCalibrations = Arc<Mutex<HashMap<(u8, u8), shutters::Calibration>>>;
+
+/// Re-send discovery and all the known state after HA restart.
+async fn reannounce(
+    config: &Config,
+    ha: &HomeAssistant,
+    state: &SharedState,
+    trackers: &SharedTrackers,
+) -> anyhow::Result<()> {
+    init_config(config, ha).await?;
+
+    // Give HA a moment to create entities before they receive state.
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+
+    let mut messages = state.lock().unwrap().snapshot();
+    {
+        let now = Instant::now();
+        let trackers = trackers.lock().unwrap();
+        messages.extend(
+            trackers
+                .iter()
+                .map(|(device, shutter, tracker)| shutter_changed(device, shutter, tracker, now)),
+        );
+    }
+    for message in messages {
+        ha.send(message).await?;
+    }
+    Ok(())
+}
+
+/// Current estimated shutter state for HA.
+fn shutter_changed(
+    device: u8,
+    shutter: u8,
+    tracker: &shutters::Tracker,
+    now: Instant,
+) -> homeassistant::Outgoing {
+    homeassistant::Outgoing::ShutterChanged {
+        device,
+        shutter,
+        direction: tracker.direction(),
+        position: tracker.position(now),
+    }
+}
+
+/// Feed output change to the shutter trackers. Returns a state update for HA
+/// if the output drives a shutter.
+fn track_shutter_output(
+    trackers: &SharedTrackers,
+    device: u8,
+    output: u8,
+    on: bool,
+) -> Option<homeassistant::Outgoing> {
+    let now = Instant::now();
+    let mut trackers = trackers.lock().unwrap();
+    let shutter = trackers.output_changed(device, output, on, now)?;
+    let tracker = trackers.get(device, shutter)?;
+    Some(shutter_changed(device, shutter, tracker, now))
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     init_log();
     let args = Args::parse();
 
-    let config = Config::from_file(&args.config_path)
-        .context(format!("Unable to read config file {}", args.config_path))?;
+    if let Some(Command::CheckConfig) = &args.command {
+        Config::from_file_checked(&args.config_path)
+            .context(format!("Invalid config file {}", args.config_path))?;
+        println!("{}: OK", args.config_path);
+        return Ok(());
+    }
+
+    let config = match &args.command {
+        // Scan helps writing the first config.
+        Some(Command::Scan { .. }) if !std::path::Path::new(&args.config_path).exists() => {
+            Config::default()
+        }
+        _ => Config::from_file_checked(&args.config_path)
+            .context(format!("Unable to read config file {}", args.config_path))?,
+    };
+    let config = Arc::new(config);
+    let settings = Settings::resolve(&args.gate(), &config.gate)?;
 
-    info!("Starting IO Gate. Args: {:?} Config: {:?}", args, config);
+    if let Some(Command::Scan { wait, write }) = &args.command {
+        return scan(&settings, &config, *wait, write.as_deref()).await;
+    }
+
+    if let Some(Command::Calibrate {
+        device,
+        shutter,
+        dry_run,
+    }) = &args.command
+    {
+        return calibrate(&args, &settings, &config, device, shutter, *dry_run).await;
+    }
 
-    let mut ha_init = homeassistant::Initiator::new(
-        "io-gate-mqtt",
-        &args.mqtt_host,
-        args.mqtt_port,
-        &args.mqtt_username,
-        &args.mqtt_password,
-    )
-    .await;
-    ha_init.set_topics("iogatetest", "iogatepub").await;
+    if settings.broker.host.is_empty() {
+        anyhow::bail!("MQTT host is required (--mqtt-host or gate.mqtt_host)");
+    }
+
+    info!(
+        "Starting IO Gate. Settings: {:?} Config: {:?}",
+        settings, config
+    );
+
+    let topics = homeassistant::Topics::new(
+        &settings.discovery_prefix,
+        &settings.control_prefix,
+        &settings.node_id,
+    );
+    let ha_init = homeassistant::Initiator::new(topics, &settings.broker).await?;
     let ha = Arc::new(ha_init.start().await);
 
     ha.send(homeassistant::Outgoing::Initial)
         .await
         .expect("Should send");
 
+    // Re-announce everything when HA restarts.
+    ha.send(homeassistant::Outgoing::Subscribe(ha.topics().ha_status()))
+        .await?;
+    // Retained discovery of all devices, to remove what's gone from config.
+    ha.send(homeassistant::Outgoing::Subscribe(
+        ha.topics().discovery_config("+"),
+    ))
+    .await?;
+    if settings.json_api {
+        ha.send(homeassistant::Outgoing::Subscribe(
+            ha.topics().api_command(),
+        ))
+        .await?;
+    }
+    if settings.raw_bus {
+        warn!("Raw bus injection is enabled");
+        ha.send(homeassistant::Outgoing::Subscribe(ha.topics().raw_inject()))
+            .await?;
+    }
+
     init_config(&config, &ha).await?;
 
-    let mut comm = comm::run(args.port_name, args.baud_rate).await?;
+    let mut comm = comm::run(settings.port_name.clone(), settings.baud_rate).await?;
 
     info!("io-gate initialized.");
 
+    let trackers: SharedTrackers = Arc::new(Mutex::new(shutters::Trackers::from_config(&config)));
+    let state: SharedState = Arc::new(Mutex::new(StateCache::default()));
+    let calibrations: SharedCalibrations = Arc::new(Mutex::new(HashMap::new()));
+
+    let (config_tx, config_rx) = watch::channel(config.clone());
+    let watcher = reload::watch_config(
+        args.config_path.clone(),
+        settings.reload_interval,
+        config_tx,
+        ha.clone(),
+        comm.tx.clone(),
+        trackers.clone(),
+    );
+    tokio::spawn(async move {
+        if let Err(err) = watcher.await {
+            error!("Config reloading stopped: {:?}", err);
+        }
+    });
+
+    // Publish estimated positions of moving shutters.
+    let ha_sender = ha.clone();
+    let shutter_trackers = trackers.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            let updates: Vec<_> = {
+                let now = Instant::now();
+                let trackers = shutter_trackers.lock().unwrap();
+                trackers
+                    .moving()
+                    .map(|(device, shutter, tracker)| {
+                        shutter_changed(device, shutter, tracker, now)
+                    })
+                    .collect()
+            };
+            for update in updates {
+                if ha_sender.send(update).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    // Cancel calibration steps nobody marks, not to leave the motor running.
+    let comm_tx = comm.tx.clone();
+    let pending = calibrations.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            let mut expired = Vec::new();
+            {
+                let now = Instant::now();
+                pending.lock().unwrap().retain(|key, calibration| {
+                    if calibration.deadline() > now {
+                        return true;
+                    }
+                    expired.push((*key, calibration.cancel()));
+                    false
+                });
+            }
+            for ((device, shutter), requests) in expired {
+                warn!(
+                    "Calibration of shutter {} of device {} timed out, cancelling",
+                    shutter, device
+                );
+                if send_outputs(&comm_tx, device, requests).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    // Publish gate diagnostics.
+    let ha_sender = ha.clone();
+    let stats = comm.stats.clone();
+    let queues = comm.queues.clone();
+    let port_name = settings.port_name.clone();
+    tokio::spawn(async move {
+        let mut last_received = 0;
+        let mut last_sent = 0;
+        loop {
+            let received = stats.received.load(Ordering::Relaxed);
+            let sent = stats.sent.load(Ordering::Relaxed);
+            let last_time_broadcast = stats.last_time_broadcast.load(Ordering::Relaxed);
+            let gate_stats = homeassistant::GateStats {
+                port: port_name.clone(),
+                received: received - last_received,
+                sent: sent - last_sent,
+                errors: stats.errors.load(Ordering::Relaxed),
+                rx_queue: queues.rx_depth(),
+                tx_queue: queues.tx_depth(),
+                mqtt_queue: ha_sender.queue_depth(),
+                last_time_broadcast: chrono::DateTime::from_timestamp(last_time_broadcast, 0)
+                    .filter(|_| last_time_broadcast > 0)
+                    .map(|time| time.to_rfc3339()),
+            };
+            last_received = received;
+            last_sent = sent;
+            if ha_sender
+                .send(homeassistant::Outgoing::GateStats(gate_stats))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+        }
+    });
+
     // CAN -> (USB -> MQTT)
     let ha_sender = ha.clone();
+    let parse_stats = comm.stats.clone();
+    let json_api = settings.json_api;
+    let raw_bus = settings.raw_bus;
+    let shutter_trackers = trackers.clone();
+    let state_cache = state.clone();
+    let known_config = config_rx.clone();
     let task_usb_to_mqtt = async move {
+        let mut unknown_devices: HashSet<u8> = HashSet::new();
         loop {
             let raw = if let Some(raw) = comm.rx.recv().await {
                 raw
@@ -123,38 +659,104 @@ async fn main() -> anyhow::Result<()> {
                 // The other end died.
                 break;
             };
+            if raw_bus {
+                let frame = homeassistant::Outgoing::RawFrame(raw.clone());
+                if ha_sender.send(frame).await.is_err() {
+                    break;
+                }
+            }
             let msg = Message::from_raw(&raw);
             let msg = if let Some(msg) = msg {
                 msg
             } else {
                 error!("Ignoring message we can't parse: {:?}", raw);
+                parse_stats.error();
                 continue;
             };
             let (device_addr, _) = raw.addr_type();
 
             info!("CAN->RX: Addr {} Message {:?}", device_addr, msg);
+            let known = scan::device_name(&known_config.borrow(), device_addr).is_some();
+            if !known && unknown_devices.insert(device_addr) {
+                warn!(
+                    "Device {} is not in the config, `io-gate scan` lists devices on the bus",
+                    device_addr
+                );
+            }
+            if json_api {
+                let event = homeassistant::Outgoing::ApiEvent {
+                    device: device_addr,
+                    message: msg.clone(),
+                };
+                if ha_sender.send(event).await.is_err() {
+                    break;
+                }
+            }
             // TODO: Push state messages
             match msg {
                 Message::InputChanged { input, trigger } => {
-                    info!("Unhandled Input Changed, ignoring: {:?} {:?}", input, trigger);
-                    continue;
+                    let result = ha_sender
+                        .send(homeassistant::Outgoing::InputTriggered {
+                            device: device_addr,
+                            input,
+                            trigger,
+                        })
+                        .await;
+                    if result.is_err() {
+                        // The other end died.
+                        break;
+                    }
+
+                    // Activation edges also drive the binary sensor state.
+                    let on = match trigger {
+                        Trigger::Activated => true,
+                        Trigger::Deactivated => false,
+                        _ => continue,
+                    };
+                    state_cache
+                        .lock()
+                        .unwrap()
+                        .set_input(device_addr, input, Some(on));
+                    let result = ha_sender
+                        .send(homeassistant::Outgoing::InputChanged {
+                            device: device_addr,
+                            input,
+                            on: Some(on),
+                        })
+                        .await;
+                    if result.is_err() {
+                        // The other end died.
+                        break;
+                    }
                 }
 
                 Message::StatusIO { io, state } => {
-                    let on = if let Some(on) = state.try_to_bool() {
-                        on
-                    } else {
-                        // TODO: Handle Error and Unknown!
-                        error!("Output state {:?} is unsupported yet", state);
-                        continue;
-                    };
+                    // Error and Unknown are published as unknown state.
+                    let on = state.try_to_bool();
+                    if on.is_none() {
+                        warn!(
+                            "Device {} reports {:?} state of {:?}",
+                            device_addr, state, io
+                        );
+                    }
 
                     match io {
                         IOType::Input(idx) => {
-                            error!("Handle input state reporting! {:?} to {:?}", io, state);
-                            // TODO
-                        },
+                            state_cache.lock().unwrap().set_input(device_addr, idx, on);
+                            let result = ha_sender
+                                .send(homeassistant::Outgoing::InputChanged {
+                                    device: device_addr,
+                                    input: idx,
+                                    on,
+                                })
+                                .await;
+                            if result.is_err() {
+                                // The other end died.
+                                break;
+                            }
+                        }
                         IOType::Output(idx) => {
+                            state_cache.lock().unwrap().set_output(device_addr, idx, on);
                             let result = ha_sender
                                 .send(homeassistant::Outgoing::OutputChanged {
                                     device: device_addr,
@@ -166,19 +768,30 @@ async fn main() -> anyhow::Result<()> {
                                 // The other end died.
                                 break;
                             }
+
+                            let update = on.and_then(|on| {
+                                track_shutter_output(&shutter_trackers, device_addr, idx, on)
+                            });
+                            if let Some(update) = update {
+                                if ha_sender.send(update).await.is_err() {
+                                    break;
+                                }
+                            }
                         }
                     }
                 }
 
                 Message::OutputChanged { output, state } => {
-                    let on = if let Some(on) = state.try_to_bool() {
-                        on
-                    } else {
-                        // TODO: Handle Error and Unknown!
-                        error!("Output state {:?} is not supported - use absolute states", state);
-                        continue;
+                    let on = {
+                        let mut cache = state_cache.lock().unwrap();
+                        match state.try_to_bool() {
+                            Some(on) => {
+                                cache.set_output(device_addr, output, Some(on));
+                                Some(on)
+                            }
+                            None => cache.toggle_output(device_addr, output),
+                        }
                     };
-
                     let result = ha_sender
                         .send(homeassistant::Outgoing::OutputChanged {
                             device: device_addr,
@@ -190,6 +803,37 @@ async fn main() -> anyhow::Result<()> {
                         // The other end died.
                         break;
                     }
+
+                    let update = on.and_then(|on| {
+                        track_shutter_output(&shutter_trackers, device_addr, output, on)
+                    });
+                    if let Some(update) = update {
+                        if ha_sender.send(update).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                Message::Status {
+                    uptime,
+                    errors,
+                    warnings,
+                } => {
+                    state_cache
+                        .lock()
+                        .unwrap()
+                        .set_status(device_addr, uptime, errors, warnings);
+                    let result = ha_sender
+                        .send(homeassistant::Outgoing::DeviceStatus {
+                            device: device_addr,
+                            uptime,
+                            errors,
+                            warnings,
+                        })
+                        .await;
+                    if result.is_err() {
+                        // The other end died.
+                        break;
+                    }
                 }
                 Message::Info { code, arg } => {
                     if code == InfoCode::Started.to_bytes() {
@@ -216,8 +860,12 @@ async fn main() -> anyhow::Result<()> {
 
     // MQTT -> USB -> CAN
     let comm_tx = comm.tx.clone();
+    let current_config = config_rx.clone();
+    let config_path = args.config_path.clone();
+    let pending = calibrations.clone();
     let task_mqtt_to_usb = async move {
-        loop {
+        let mut output_timers: HashMap<(u8, u8), tokio::task::AbortHandle> = HashMap::new();
+        'messages: loop {
             let msg = if let Some(msg) = ha.recv().await {
                 msg
             } else {
@@ -226,13 +874,56 @@ async fn main() -> anyhow::Result<()> {
             };
 
             match msg {
-                homeassistant::Incoming::RawTest(_vec) => {
-                    info!("Raw test message received");
+                homeassistant::Incoming::HomeAssistantOnline => {
+                    info!("Home Assistant is online, announcing devices and state");
+                    let ha = ha.clone();
+                    let config = current_config.borrow().clone();
+                    let state = state.clone();
+                    let trackers = trackers.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = reannounce(&config, &ha, &state, &trackers).await {
+                            error!("Unable to re-announce devices: {:?}", err);
+                        }
+                    });
+                }
+                homeassistant::Incoming::Announced(announced) => {
+                    let config = current_config.borrow().clone();
+                    let updates = discovery::cleanup(ha.topics(), &config, &announced);
+                    for update in updates {
+                        if ha.send(update).await.is_err() {
+                            break 'messages;
+                        }
+                    }
+                }
+                homeassistant::Incoming::Api { device, message } => {
+                    let raw = message.to_raw(device);
+                    info!("Sending API message {:?} to device {}", message, device);
+                    if comm_tx.send(raw).await.is_err() {
+                        // The other side died.
+                        break;
+                    }
+                }
+                homeassistant::Incoming::RawFrame(raw) => {
+                    info!("Injecting raw frame {}", raw.to_text());
+                    if comm_tx.send(raw).await.is_err() {
+                        // The other side died.
+                        break;
+                    }
                 }
-                homeassistant::Incoming::SetOutput { device, output, on } => {
+                homeassistant::Incoming::SetOutput {
+                    device,
+                    output,
+                    state: request,
+                    duration,
+                } => {
+                    // New request overrides the pending revert.
+                    if let Some(timer) = output_timers.remove(&(device, output)) {
+                        timer.abort();
+                    }
+
                     let msg = Message::SetOutput {
                         output,
-                        state: OutputChangeRequest::from_bool(on),
+                        state: request,
                     };
                     let addr = device;
                     let raw = msg.to_raw(addr);
@@ -241,6 +932,193 @@ async fn main() -> anyhow::Result<()> {
                         // The other side died.
                         break;
                     }
+
+                    // Firmware has no time-limited outputs; revert from here.
+                    if let Some(duration) = duration {
+                        let revert = Message::SetOutput {
+                            output,
+                            state: request.inverse(),
+                        }
+                        .to_raw(device);
+                        let comm_tx = comm_tx.clone();
+                        let timer = tokio::spawn(async move {
+                            tokio::time::sleep(duration).await;
+                            info!("Reverting timed output {} of device {}", output, device);
+                            let _ = comm_tx.send(revert).await;
+                        });
+                        // Forget timers that already fired.
+                        output_timers.retain(|_, timer| !timer.is_finished());
+                        output_timers.insert((device, output), timer.abort_handle());
+                    }
+
+                    // Device won't report a change if it's already in the
+                    // requested state - answer HA from the cache.
+                    let Some(on) = request.try_to_bool() else {
+                        continue;
+                    };
+                    let cached = state.lock().unwrap().output(device, output);
+                    if cached == Some(on) {
+                        let update = homeassistant::Outgoing::OutputChanged {
+                            device,
+                            output,
+                            on: cached,
+                        };
+                        if ha.send(update).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                homeassistant::Incoming::TriggerInput {
+                    device,
+                    input,
+                    trigger,
+                } => {
+                    let msg = Message::TriggerInput { input, trigger };
+                    let raw = msg.to_raw(device);
+                    info!(
+                        "Simulating {:?} on input {} of device {}",
+                        trigger, input, device
+                    );
+                    if comm_tx.send(raw).await.is_err() {
+                        // The other side died.
+                        break;
+                    }
+                }
+                homeassistant::Incoming::CallProcedure { device, proc_id } => {
+                    let msg = Message::CallProcedure { proc_id };
+                    let raw = msg.to_raw(device);
+                    info!("Calling procedure {} on device {}", proc_id, device);
+                    if comm_tx.send(raw).await.is_err() {
+                        // The other side died.
+                        break;
+                    }
+                }
+                homeassistant::Incoming::ShutterCalibration {
+                    device,
+                    shutter,
+                    cmd,
+                } => {
+                    let now = Instant::now();
+                    let (requests, step, result) = {
+                        let mut calibrations = pending.lock().unwrap();
+                        let requests = match (cmd, calibrations.get_mut(&(device, shutter))) {
+                            (CalibrationCommand::Start, Some(_)) => {
+                                warn!("Calibration of shutter {} already in progress", shutter);
+                                continue;
+                            }
+                            (CalibrationCommand::Start, None) => {
+                                let outputs = trackers
+                                    .lock()
+                                    .unwrap()
+                                    .get(device, shutter)
+                                    .map(|tracker| (tracker.down, tracker.up));
+                                let Some((down, up)) = outputs else {
+                                    warn!("Calibration of unconfigured shutter {}", shutter);
+                                    continue;
+                                };
+                                let (calibration, requests) =
+                                    shutters::Calibration::start(down, up, now);
+                                calibrations.insert((device, shutter), calibration);
+                                requests
+                            }
+                            (CalibrationCommand::Mark, Some(calibration)) => calibration.mark(now),
+                            (CalibrationCommand::Cancel, Some(calibration)) => {
+                                let requests = calibration.cancel();
+                                calibrations.remove(&(device, shutter));
+                                requests
+                            }
+                            (_, None) => {
+                                warn!("No calibration of shutter {} in progress", shutter);
+                                continue;
+                            }
+                        };
+                        let step = calibrations.get(&(device, shutter)).map(|c| c.step());
+                        let result = match step {
+                            Some(shutters::CalibrationStep::Done) => calibrations
+                                .remove(&(device, shutter))
+                                .and_then(|c| c.result()),
+                            _ => None,
+                        };
+                        (requests, step, result)
+                    };
+                    if send_outputs(&comm_tx, device, requests).await.is_err() {
+                        break;
+                    }
+
+                    let Some(step) = step else {
+                        continue;
+                    };
+                    info!(
+                        "Calibrating shutter {} of device {}: {}",
+                        shutter,
+                        device,
+                        step.instructions()
+                    );
+                    let Some(timings) = result else {
+                        continue;
+                    };
+                    info!("Measured shutter timings {:?}", timings);
+                    if let Err(err) = timings.check_measured() {
+                        error!("Discarding calibration of shutter {}: {}", shutter, err);
+                        continue;
+                    }
+
+                    if let Some(tracker) = trackers.lock().unwrap().get_mut(device, shutter) {
+                        tracker.set_timings(timings);
+                    }
+                    if let Err(err) =
+                        config::store_shutter_timings(&config_path, device, shutter, &timings)
+                    {
+                        error!("Unable to store shutter timings in config: {:?}", err);
+                    }
+                    let over = current_config
+                        .borrow()
+                        .shutter(device, shutter)
+                        .map_or(config::DEFAULT_OVER_TIME, |cfg| cfg.over_time());
+                    for cmd in timings.commands(over) {
+                        let msg = Message::ShutterCmd {
+                            shutter_idx: shutter,
+                            cmd,
+                        };
+                        if comm_tx.send(msg.to_raw(device)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                homeassistant::Incoming::Shutter {
+                    device,
+                    shutter,
+                    cmd,
+                } => {
+                    let cmd = {
+                        let mut trackers = trackers.lock().unwrap();
+                        match (trackers.get_mut(device, shutter), cmd) {
+                            (Some(tracker), cmd) => {
+                                let cmd = cmd.to_cmd(tracker.position(Instant::now()));
+                                tracker.command(&cmd);
+                                cmd
+                            }
+                            // The JSON API also drives shutters missing in the config.
+                            (None, CoverCommand::Driver(cmd)) => cmd,
+                            (None, _) => {
+                                warn!(
+                                    "Command for unconfigured shutter {} of device {}",
+                                    shutter, device
+                                );
+                                continue;
+                            }
+                        }
+                    };
+                    let msg = Message::ShutterCmd {
+                        shutter_idx: shutter,
+                        cmd,
+                    };
+                    let raw = msg.to_raw(device);
+                    info!("Sending shutter command {:?} over USB {:?}", msg, raw);
+                    if comm_tx.send(raw).await.is_err() {
+                        // The other side died.
+                        break;
+                    }
                 }
             }
         }
@@ -249,12 +1127,48 @@ async fn main() -> anyhow::Result<()> {
         Err::<(), ()>(())
     };
 
+    if settings.status_interval > 0 {
+        let comm_tx = comm.tx.clone();
+        let config = config_rx.clone();
+        let interval = std::time::Duration::from_secs(settings.status_interval);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let config = config.borrow().clone();
+                for device in config.devices.values() {
+                    let raw = Message::RequestStatus.to_raw(device.addr);
+                    if comm_tx.send(raw).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
     let comm_tx = comm.tx.clone();
+    let time_stats = comm.stats.clone();
     tokio::spawn(async move {
         tokio::time::sleep(std::time::Duration::from_secs(1)).await;
 
+        // Configure shutter drivers before anyone uses them.
+        for device in config.devices.values() {
+            for shutter in device.shutters.values() {
+                for cmd in shutter.commands() {
+                    let msg = Message::ShutterCmd {
+                        shutter_idx: shutter.id,
+                        cmd,
+                    };
+                    let raw = msg.to_raw(device.addr);
+                    if comm_tx.send(raw).await.is_err() {
+                        error!("Unable to send message to configure shutter");
+                        break;
+                    }
+                }
+            }
+        }
+
         // Start by querying all configured devices to tell us their current state.
-        for (_name, device) in &config.devices {
+        for device in config.devices.values() {
             tokio::time::sleep(std::time::Duration::from_secs(2)).await;
 
             let msg = Message::RequestStatus;
@@ -292,11 +1206,29 @@ async fn main() -> anyhow::Result<()> {
                 // Transmitter died.
                 break;
             }
+            time_stats
+                .last_time_broadcast
+                .store(now.timestamp(), Ordering::Relaxed);
         }
     });
 
     // Wait for tasks. If any side dies (comm.reader, comm.writer) this should
     // close the program.
-    let _ = tokio::try_join!(task_mqtt_to_usb, task_usb_to_mqtt);
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = async { tokio::try_join!(task_mqtt_to_usb, task_usb_to_mqtt) } => {}
+        _ = terminate.recv() => info!("SIGTERM received, stopping"),
+        _ = tokio::signal::ctrl_c() => info!("Interrupted, stopping"),
+    }
+
+    // Outputs driven by a calibration would stay on.
+    let pending: Vec<_> = calibrations.lock().unwrap().drain().collect();
+    if !pending.is_empty() {
+        for ((device, _), calibration) in pending {
+            let _ = send_outputs(&comm.tx, device, calibration.cancel()).await;
+        }
+        // Let the writer flush the queue.
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    }
     Ok(())
 }
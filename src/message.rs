@@ -1,5 +1,6 @@
 // Message parsing parts moved from io-ctrl. TODO: Maybe move to a shared crate.
-use tracing::{info, warn, error};
+use serde::Serialize;
+use tracing::{error, warn};
 
 use crate::{
     consts::{InIdx, OutIdx, ProcIdx, ShutterIdx},
@@ -72,6 +73,7 @@ mod msg_type {
 
 pub mod args {
     pub use crate::consts::{InIdx, OutIdx, Trigger};
+    use serde::{Deserialize, Serialize};
 
     #[derive(Clone, Copy, Debug)]
     #[repr(u16)]
@@ -79,7 +81,8 @@ pub mod args {
         Started = 10,
     }
 
-    #[derive(Clone, Copy, Debug)]
+    #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
     #[repr(u8)]
     pub enum OutputChangeRequest {
         /// Disable output
@@ -90,7 +93,8 @@ pub mod args {
         Toggle = 2,
     }
 
-    #[derive(Clone, Copy, Debug)]
+    #[derive(Clone, Copy, Debug, Serialize)]
+    #[serde(rename_all = "snake_case")]
     #[repr(u8)]
     pub enum IOState {
         /// Input/Output is disabled.
@@ -103,7 +107,8 @@ pub mod args {
         Unknown = 3,
     }
 
-    #[derive(Clone, Copy, Debug)]
+    #[derive(Clone, Copy, Debug, Serialize)]
+    #[serde(rename_all = "snake_case")]
     #[repr(u8)]
     pub enum IOType {
         /// Idx describes an Input.
@@ -174,13 +179,48 @@ pub mod args {
                 Self::Toggle => None,
             }
         }
+
+        /// Request undoing this one.
+        pub fn inverse(self) -> Self {
+            match self {
+                Self::Off => Self::On,
+                Self::On => Self::Off,
+                Self::Toggle => Self::Toggle,
+            }
+        }
     }
 
     impl Trigger {
+        /// All triggers, in the order of their codes.
+        pub const ALL: [Trigger; 6] = [
+            Trigger::ShortClick,
+            Trigger::LongClick,
+            Trigger::Activated,
+            Trigger::Deactivated,
+            Trigger::LongActivated,
+            Trigger::LongDeactivated,
+        ];
+
         pub fn to_bytes(self) -> u8 {
             self as u8
         }
 
+        /// Name used in MQTT payloads and HA event types.
+        pub fn name(self) -> &'static str {
+            match self {
+                Trigger::ShortClick => "short_click",
+                Trigger::LongClick => "long_click",
+                Trigger::Activated => "activated",
+                Trigger::Deactivated => "deactivated",
+                Trigger::LongActivated => "long_activated",
+                Trigger::LongDeactivated => "long_deactivated",
+            }
+        }
+
+        pub fn from_name(name: &str) -> Option<Self> {
+            Self::ALL.into_iter().find(|trigger| trigger.name() == name)
+        }
+
         pub fn from_u8(raw: u8) -> Option<Self> {
             match raw {
                 0 => Some(Trigger::ShortClick),
@@ -195,8 +235,10 @@ pub mod args {
     }
 }
 
-/// This holds the decoded message internally.
-#[derive(Debug)]
+/// This holds the decoded message internally. Serializes to JSON tagged with
+/// the `type`, eg. `{"type": "output_changed", "output": 1, "state": "on"}`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum Message {
     // Start with rare important events.
     /// Erroneous situation happened. Includes error code.
@@ -211,6 +253,7 @@ pub enum Message {
     },
 
     /// Input/output state (not changed - just current.)
+    #[serde(rename = "status_io")]
     StatusIO {
         io: args::IOType,
         state: args::IOState,
@@ -294,7 +337,7 @@ pub enum Message {
 }
 
 /// Raw message prepared for sending or just received.
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct MessageRaw {
     /// "Device" address - either source (for responses/status), or destination (for requests)
     addr: u8,
@@ -352,6 +395,35 @@ impl MessageRaw {
     pub fn data_as_slice(&self) -> &[u8] {
         &self.data[0..self.length as usize]
     }
+
+    /// Text form as used by candump: hex CAN ID and data, eg. `101#0301`
+    pub fn to_text(&self) -> String {
+        let data: String = self
+            .data_as_slice()
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        format!("{:03X}#{}", self.to_can_addr(), data)
+    }
+
+    /// Parse the candump-like text form.
+    pub fn from_text(text: &str) -> Option<Self> {
+        let (can_addr, data) = text.trim().split_once('#')?;
+        // from_str_radix would take a sign too.
+        let is_hex = |text: &str| text.bytes().all(|byte| byte.is_ascii_hexdigit());
+        if !is_hex(can_addr) || !is_hex(data) {
+            return None;
+        }
+        let can_addr = u16::from_str_radix(can_addr, 16).ok()?;
+        if can_addr > 0x7FF || data.len() % 2 != 0 || data.len() > 16 {
+            return None;
+        }
+        let data = (0..data.len())
+            .step_by(2)
+            .map(|idx| u8::from_str_radix(data.get(idx..idx + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some(Self::from_can(can_addr, &data))
+    }
 }
 
 impl Message {
@@ -415,6 +487,23 @@ impl Message {
                 body: u16::from_le_bytes([raw.data[0], raw.data[1]]),
             }),
 
+            msg_type::STATUS => {
+                if raw.length != 8 {
+                    error!("Status has invalid message length {:?}", raw);
+                    return None;
+                }
+                Some(Message::Status {
+                    uptime: u32::from_le_bytes([
+                        raw.data[0],
+                        raw.data[1],
+                        raw.data[2],
+                        raw.data[3],
+                    ]),
+                    errors: u16::from_le_bytes([raw.data[4], raw.data[5]]),
+                    warnings: u16::from_le_bytes([raw.data[6], raw.data[7]]),
+                })
+            }
+
             msg_type::INFO => {
                 let code: u16 = u16::from_le_bytes([raw.data[0], raw.data[1]]);
                 let arg: u32 = 0;
@@ -469,18 +558,22 @@ impl Message {
                     0 => args::IOType::Input(idx),
                     1 => args::IOType::Output(idx),
                     _ => {
-                        error!("StatusIO has invalid type argument (not 0, not 1) {:?}", raw);
+                        error!(
+                            "StatusIO has invalid type argument (not 0, not 1) {:?}",
+                            raw
+                        );
                         return None;
                     }
                 };
-                Some(Message::StatusIO {
-                    state, io
-                })
+                Some(Message::StatusIO { state, io })
             }
 
             _ => {
                 // TBH, probably safe to ignore.
-                warn!("Unable to parse unhandled message type {:?}. Message: {:?}", raw.msg_type, raw);
+                warn!(
+                    "Unable to parse unhandled message type {:?}. Message: {:?}",
+                    raw.msg_type, raw
+                );
                 None
             }
         }
@@ -604,16 +697,61 @@ impl Message {
             Message::RequestStatus => {
                 raw.msg_type = msg_type::REQUEST_STATUS;
                 raw.length = 0;
-            }
-
-            /*
-              TODO: Remote bytecode update.
-              Message::MicrocodeUpdateInit { addr, length } => todo!(),
-              Message::MicrocodeUpdatePart { offset, chunk } => todo!(),
-              Message::MicrocodeUpdateEnd { chunks, length, crc } => todo!(),
-              Message::MicrocodeUpdateAck { length } => todo!(),
-             */
+            } /*
+               TODO: Remote bytecode update.
+               Message::MicrocodeUpdateInit { addr, length } => todo!(),
+               Message::MicrocodeUpdatePart { offset, chunk } => todo!(),
+               Message::MicrocodeUpdateEnd { chunks, length, crc } => todo!(),
+               Message::MicrocodeUpdateAck { length } => todo!(),
+              */
         }
         raw
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_round_trip() {
+        let raw = Message::SetOutput {
+            output: 3,
+            state: args::OutputChangeRequest::Toggle,
+        }
+        .to_raw(5);
+        let text = raw.to_text();
+        assert_eq!(text, format!("{:03X}#0302", raw.to_can_addr()));
+
+        let parsed = MessageRaw::from_text(&text).unwrap();
+        assert_eq!(parsed.addr_type(), raw.addr_type());
+        assert_eq!(parsed.data_as_slice(), raw.data_as_slice());
+
+        let parsed = MessageRaw::from_text(" 7ff#0011223344556677\n").unwrap();
+        assert_eq!(parsed.to_can_addr(), 0x7FF);
+        assert_eq!(parsed.to_text(), "7FF#0011223344556677");
+
+        // No data.
+        let parsed = MessageRaw::from_text("041#").unwrap();
+        assert_eq!(parsed.addr_type(), (1, 1));
+        assert_eq!(parsed.length(), 0);
+        assert_eq!(parsed.to_text(), "041#");
+    }
+
+    #[test]
+    fn text_rejected() {
+        // CAN ID over 11 bits.
+        assert!(MessageRaw::from_text("800#00").is_none());
+        // Odd hex length.
+        assert!(MessageRaw::from_text("101#030").is_none());
+        // More than 8 bytes.
+        assert!(MessageRaw::from_text("101#001122334455667788").is_none());
+        // Missing separator.
+        assert!(MessageRaw::from_text("1010302").is_none());
+        // Not hex.
+        assert!(MessageRaw::from_text("101#0g").is_none());
+        assert!(MessageRaw::from_text("101#+1").is_none());
+        assert!(MessageRaw::from_text("+11#00").is_none());
+        assert!(MessageRaw::from_text("#00").is_none());
+    }
+}
@@ -0,0 +1,192 @@
+// Bus scan: collect what devices answer and describe them as config.
+use crate::config::Config;
+use crate::message::args::{IOState, IOType, InfoCode};
+use crate::message::Message;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+
+/// Everything a device told us during the scan.
+#[derive(Debug, Default)]
+pub struct Found {
+    pub outputs: BTreeMap<u8, IOState>,
+    pub inputs: BTreeMap<u8, IOState>,
+    /// Uptime, errors and warnings from the Status message.
+    pub status: Option<(u32, u16, u16)>,
+    pub answered_ping: bool,
+    /// Device reported it has just started.
+    pub started: bool,
+    pub errors: Vec<u32>,
+}
+
+/// Devices found on the bus by address.
+#[derive(Debug, Default)]
+pub struct Scan {
+    pub devices: BTreeMap<u8, Found>,
+}
+
+impl Scan {
+    /// Note a message received during the scan.
+    pub fn handle(&mut self, addr: u8, msg: &Message) {
+        let found = self.devices.entry(addr).or_default();
+        match msg {
+            Message::StatusIO {
+                io: IOType::Output(idx),
+                state,
+            } => {
+                found.outputs.insert(*idx, *state);
+            }
+            Message::StatusIO {
+                io: IOType::Input(idx),
+                state,
+            } => {
+                found.inputs.insert(*idx, *state);
+            }
+            Message::Status {
+                uptime,
+                errors,
+                warnings,
+            } => found.status = Some((*uptime, *errors, *warnings)),
+            Message::Pong { .. } => found.answered_ping = true,
+            Message::Info { code, .. } if *code == InfoCode::Started.to_bytes() => {
+                found.started = true
+            }
+            Message::Error { code } => found.errors.push(*code),
+            _ => {}
+        }
+    }
+
+    /// Human readable report. Devices are named after the config when known.
+    pub fn report(&self, config: &Config) -> String {
+        let mut report = format!("Found {} device(s)\n", self.devices.len());
+        for (addr, found) in &self.devices {
+            let name = device_name(config, *addr);
+            let _ = write!(
+                report,
+                "{:#04x} {}: {} outputs, {} inputs",
+                addr,
+                name.unwrap_or("(not in config)"),
+                found.outputs.len(),
+                found.inputs.len(),
+            );
+            if let Some((uptime, errors, warnings)) = found.status {
+                let _ = write!(
+                    report,
+                    ", uptime {}s, {} errors, {} warnings",
+                    uptime, errors, warnings
+                );
+            }
+            if !found.errors.is_empty() {
+                let _ = write!(report, ", error codes {:?}", found.errors);
+            }
+            report.push('\n');
+            for (kind, ios) in [("outputs", &found.outputs), ("inputs", &found.inputs)] {
+                if !ios.is_empty() {
+                    let _ = writeln!(report, "    {}: {}", kind, States(ios));
+                }
+            }
+        }
+        report
+    }
+
+    /// Config skeleton of the devices found, with IOs named by index.
+    pub fn skeleton(&self) -> String {
+        let mut yaml = String::from(
+            "# Generated by `io-gate scan`. All IOs found are exposed to Home Assistant\n\
+             # under placeholder labels. Name the devices and IOs; to expose only some\n\
+             # IOs, replace `template` with `labels` and use ~ for the unused ones.\n",
+        );
+        for (addr, found) in &self.devices {
+            let _ = write!(yaml, "\ndevice-{}:\n  addr: {}\n", addr, addr);
+            for (kind, prefix, ios) in [
+                ("outputs", "out", &found.outputs),
+                ("inputs", "in", &found.inputs),
+            ] {
+                // Index 0 is reserved.
+                let mut indexes = ios.keys().filter(|idx| **idx > 0);
+                match (indexes.next(), indexes.next_back()) {
+                    (Some(first), last) => {
+                        let count = *last.unwrap_or(first) as u16 - *first as u16 + 1;
+                        let _ = write!(
+                            yaml,
+                            "  {}:\n    first: {}\n    count: {}\n    template: \"{}-{{}}\"\n",
+                            kind, first, count, prefix
+                        );
+                    }
+                    _ => {
+                        let _ = writeln!(yaml, "  {}: {{}}", kind);
+                    }
+                }
+            }
+        }
+        yaml
+    }
+}
+
+/// Name of the device with a given address in config.
+pub fn device_name(config: &Config, addr: u8) -> Option<&str> {
+    config
+        .devices
+        .iter()
+        .find(|(_, device)| device.addr == addr)
+        .map(|(name, _)| name.as_str())
+}
+
+/// IO states as `1=on 2=off ...`
+struct States<'a>(&'a BTreeMap<u8, IOState>);
+
+impl fmt::Display for States<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (n, (idx, state)) in self.0.iter().enumerate() {
+            if n > 0 {
+                write!(f, " ")?;
+            }
+            let state = match state {
+                IOState::Off => "off",
+                IOState::On => "on",
+                IOState::Error => "error",
+                IOState::Unknown => "unknown",
+            };
+            write!(f, "{}={}", idx, state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skeleton_is_valid_config() {
+        let mut scan = Scan::default();
+        let status = |io, state| Message::StatusIO { io, state };
+        // Index 0 is reserved, 255 is the highest.
+        for idx in [0, 1, 2, 255] {
+            scan.handle(1, &status(IOType::Output(idx), IOState::Off));
+        }
+        scan.handle(1, &status(IOType::Input(3), IOState::On));
+        scan.handle(1, &status(IOType::Input(5), IOState::Unknown));
+        scan.handle(2, &Message::Pong { body: 2 });
+        scan.handle(3, &status(IOType::Input(0), IOState::Off));
+
+        let skeleton = scan.skeleton();
+        let config: Config = serde_yaml::from_str(&skeleton).unwrap();
+        let problems: Vec<String> = config
+            .check(&skeleton)
+            .iter()
+            .map(|problem| problem.to_string())
+            .collect();
+        assert_eq!(problems, Vec::<String>::new(), "{}", skeleton);
+
+        let device = &config.devices["device-1"];
+        assert_eq!(device.addr, 1);
+        assert_eq!(device.outputs.len(), 255);
+        assert_eq!(device.outputs["out-255"].id, 255);
+        assert_eq!(device.inputs.len(), 3);
+        assert_eq!(device.inputs["in-4"].id, 4);
+        for name in ["device-2", "device-3"] {
+            let device = &config.devices[name];
+            assert!(device.outputs.is_empty() && device.inputs.is_empty());
+        }
+    }
+}
@@ -1,7 +1,14 @@
-use crate::consts::OutIdx;
+use crate::config::Config;
+use crate::consts::{OutIdx, ShutterIdx};
+use serde::de::{Deserializer, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 /// Internal commands handled by a shutter driver.
-#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 #[repr(u8)]
 pub enum Cmd {
     /// Full analog control: change height and tilt to given values 0-100.
@@ -14,7 +21,7 @@ pub enum Cmd {
     Close,
 
     /// Keep height and change tilt to given 0-100.
-    Tilt(u8),
+    Tilt(#[serde(deserialize_with = "deserialize_percent")] u8),
 
     // Tilt helpers.
     /// Tilt(100) - completely closed.
@@ -27,9 +34,12 @@ pub enum Cmd {
     TiltReverse,
 
     /// Shutters are configured with commands.
+    #[serde(rename = "set_io")]
     SetIO(/* down */ OutIdx, /* up */ OutIdx),
-    // TODO SetRiseDropTime(u16, u16),
-    // TODO SetTiltOverTime(u16, u16),
+    /// Full travel times in milliseconds.
+    SetRiseDropTime(/* rise */ u16, /* drop */ u16),
+    /// Full tilt time and end-stop over time in milliseconds.
+    SetTiltOverTime(/* tilt */ u16, /* over */ u16),
 }
 
 mod codes {
@@ -42,6 +52,8 @@ mod codes {
     pub const TILT_HALF: u8 = 0x07;
     pub const TILT_REVERSE: u8 = 0x08;
     pub const SET_IO: u8 = 0x10;
+    pub const SET_RISE_DROP_TIME: u8 = 0x11;
+    pub const SET_TILT_OVER_TIME: u8 = 0x12;
 }
 impl Cmd {
     pub fn from_raw(raw: &[u8; 5]) -> Option<Self> {
@@ -50,11 +62,19 @@ impl Cmd {
             codes::OPEN => Cmd::Open,
             codes::CLOSE => Cmd::Close,
             codes::TILT => Cmd::Tilt(raw[1]),
-            codes::TILT_CLOSE => Cmd::Close,
+            codes::TILT_CLOSE => Cmd::TiltClose,
             codes::TILT_OPEN => Cmd::TiltOpen,
             codes::TILT_HALF => Cmd::TiltHalf,
             codes::TILT_REVERSE => Cmd::TiltReverse,
             codes::SET_IO => Cmd::SetIO(raw[1], raw[2]),
+            codes::SET_RISE_DROP_TIME => Cmd::SetRiseDropTime(
+                u16::from_le_bytes([raw[1], raw[2]]),
+                u16::from_le_bytes([raw[3], raw[4]]),
+            ),
+            codes::SET_TILT_OVER_TIME => Cmd::SetTiltOverTime(
+                u16::from_le_bytes([raw[1], raw[2]]),
+                u16::from_le_bytes([raw[3], raw[4]]),
+            ),
             _ => {
                 return None;
             }
@@ -97,22 +117,65 @@ impl Cmd {
                 raw[1] = *down;
                 raw[2] = *up;
             }
+            Cmd::SetRiseDropTime(rise, drop) => {
+                raw[0] = codes::SET_RISE_DROP_TIME;
+                raw[1..3].copy_from_slice(&rise.to_le_bytes());
+                raw[3..5].copy_from_slice(&drop.to_le_bytes());
+            }
+            Cmd::SetTiltOverTime(tilt, over) => {
+                raw[0] = codes::SET_TILT_OVER_TIME;
+                raw[1..3].copy_from_slice(&tilt.to_le_bytes());
+                raw[3..5].copy_from_slice(&over.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Single coordinate of a position: either synchronized with reality (an end
+/// stop was reached), or guessed from timing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Estimate {
+    /// Position confirmed by reaching an end stop and tracked since.
+    Known(f32),
+    /// Position not synchronized (eg. after restart).
+    Guessed(f32),
+}
+
+impl Estimate {
+    pub fn value(&self) -> f32 {
+        match self {
+            Self::Known(value) | Self::Guessed(value) => *value,
+        }
+    }
+
+    pub fn is_known(&self) -> bool {
+        matches!(self, Self::Known(_))
+    }
+
+    /// Rounded to 0-100 percent.
+    pub fn percent(&self) -> u8 {
+        self.value().round().clamp(0.0, 100.0) as u8
+    }
+
+    /// Same accuracy, different value.
+    fn with_value(&self, value: f32) -> Self {
+        match self {
+            Self::Known(_) => Self::Known(value),
+            Self::Guessed(_) => Self::Guessed(value),
         }
     }
 }
 
 /// Current shutter position, or partial position during computation.
 #[derive(Debug, Clone, Copy, PartialEq)]
-struct Position {
+pub struct Position {
     // Accuracy should allow for 1ms resolution of time. Height 0-100 in 60s
     // would mean 1% takes 600ms. 65535 would have 0.92ms resolution, but we
     // would have to convert. f32 is fine on stm32g4.
-
-    // TODO: Height/Tilt should be an Enum - Known / Guessed. To mark when the position is not synchronized.
     /// Position of shutters. 0 (open) - 100% (closed)
-    height: f32,
+    pub height: Estimate,
     /// 0 (open) - 100% (closed)
-    tilt: f32,
+    pub tilt: Estimate,
 }
 
 impl Position {
@@ -120,39 +183,674 @@ impl Position {
         assert!(height <= 100);
         assert!(tilt <= 100);
         Self {
-            height: height as f32,
-            tilt: tilt as f32,
+            height: Estimate::Known(height as f32),
+            tilt: Estimate::Known(tilt as f32),
         }
     }
 
     pub fn new_zero() -> Self {
         Self {
-            height: 0.0,
-            tilt: 0.0,
+            height: Estimate::Known(0.0),
+            tilt: Estimate::Known(0.0),
         }
     }
-}
 
+    /// Position after moving in a given direction for `elapsed` seconds.
+    /// Tilt changes first, then the height. Reaching an end stop synchronizes
+    /// the coordinate.
+    fn advance(&self, direction: Direction, elapsed: f32, timings: &Timings) -> Self {
+        let (tilt_end, height_end, travel) = match direction {
+            Direction::Down => (100.0, 100.0, timings.drop),
+            Direction::Up => (0.0, 0.0, timings.rise),
+        };
+
+        let tilt_left = (tilt_end - self.tilt.value()).abs();
+        let tilt_time = tilt_left / 100.0 * timings.tilt;
+        if elapsed < tilt_time {
+            let delta = elapsed / timings.tilt * 100.0;
+            let tilt = match direction {
+                Direction::Down => self.tilt.value() + delta,
+                Direction::Up => self.tilt.value() - delta,
+            };
+            return Self {
+                height: self.height,
+                tilt: self.tilt.with_value(tilt),
+            };
+        }
+
+        let height_left = (height_end - self.height.value()).abs();
+        let delta = (elapsed - tilt_time) / travel * 100.0;
+        let height = if delta >= height_left {
+            Estimate::Known(height_end)
+        } else {
+            let height = match direction {
+                Direction::Down => self.height.value() + delta,
+                Direction::Up => self.height.value() - delta,
+            };
+            self.height.with_value(height)
+        };
+
+        Self {
+            height,
+            tilt: Estimate::Known(tilt_end),
+        }
+    }
+}
 
 /// Planned target shutter position.
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
 pub struct TargetPosition {
     // We stick to 0-100% by 1% accuracy.
     /// Position of shutters. 0 (open) - 100% (closed)
+    #[serde(deserialize_with = "deserialize_percent")]
     height: u8,
     /// 0 (open) - 100% (closed)
+    #[serde(deserialize_with = "deserialize_percent")]
     tilt: u8,
 }
 
+/// Percent value, 0-100.
+fn deserialize_percent<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    let percent = u8::deserialize(deserializer)?;
+    if percent > 100 {
+        return Err(D::Error::custom(format!(
+            "{} is out of range (0-100)",
+            percent
+        )));
+    }
+    Ok(percent)
+}
+
 impl TargetPosition {
     pub fn new(height: u8, tilt: u8) -> Self {
         Self { height, tilt }
     }
 
-    fn as_position(&self) -> Position {
+    /// Position we will be in once the target is reached.
+    pub fn as_position(&self) -> Position {
         Position {
-            height: self.height as f32,
-            tilt: self.tilt as f32,
+            height: Estimate::Guessed(self.height as f32),
+            tilt: Estimate::Guessed(self.tilt as f32),
+        }
+    }
+}
+
+/// Shutter movement direction.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Direction {
+    /// Rising, opening.
+    Up,
+    /// Dropping, closing.
+    Down,
+}
+
+/// Shutter travel times, in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timings {
+    /// Full travel from closed to open.
+    pub rise: f32,
+    /// Full travel from open to closed.
+    pub drop: f32,
+    /// Full tilt from open to closed (or back).
+    pub tilt: f32,
+}
+
+impl Timings {
+    /// Commands passing the timings to the firmware shutter driver.
+    pub fn commands(&self, over: f32) -> [Cmd; 2] {
+        [
+            Cmd::SetRiseDropTime(to_millis(self.rise), to_millis(self.drop)),
+            Cmd::SetTiltOverTime(to_millis(self.tilt), to_millis(over)),
+        ]
+    }
+
+    /// Reject measured times too short to be real, like marks sent twice.
+    pub fn check_measured(&self) -> Result<(), String> {
+        let times = [
+            ("rise", self.rise),
+            ("drop", self.drop),
+            ("tilt", self.tilt),
+        ];
+        for (name, seconds) in times {
+            if seconds.is_nan() || seconds < MIN_MEASURED_TIME {
+                return Err(format!(
+                    "Measured {} time {:.2}s is too short",
+                    name, seconds
+                ));
+            }
+            if seconds > MAX_TIME {
+                return Err(format!(
+                    "Measured {} time {:.2}s is over the longest {}s",
+                    name, seconds, MAX_TIME
+                ));
+            }
         }
+        Ok(())
+    }
+}
+
+/// Shortest measured time taken as real, in seconds.
+pub const MIN_MEASURED_TIME: f32 = 0.2;
+
+/// Longest calibration step in full travels with default timings. The
+/// calibration is cancelled then, the motor isn't left powered when the
+/// user walks away.
+const MAX_STEP_TRAVELS: f32 = 3.0;
+
+impl Default for Timings {
+    fn default() -> Self {
+        Self {
+            rise: 60.0,
+            drop: 60.0,
+            tilt: 1.5,
+        }
+    }
+}
+
+/// Longest time the firmware takes, in seconds.
+pub const MAX_TIME: f32 = u16::MAX as f32 / 1000.0;
+
+/// Convert seconds to milliseconds used by the firmware.
+pub fn to_millis(seconds: f32) -> u16 {
+    (seconds * 1000.0).round().clamp(0.0, u16::MAX as f32) as u16
+}
+
+/// Step of the calibration procedure. Each step ends with a user mark.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CalibrationStep {
+    /// Rising to the top to start from a known position.
+    Homing,
+    /// Dropping; slats are closing.
+    DropTilt,
+    /// Dropping; slats closed, shutter is going down.
+    Drop,
+    /// Rising; slats are opening.
+    RiseTilt,
+    /// Rising; slats open, shutter is going up.
+    Rise,
+    /// All times measured.
+    Done,
+}
+
+impl CalibrationStep {
+    /// What the user should do to finish the step.
+    pub fn instructions(&self) -> &'static str {
+        match self {
+            Self::Homing => "Shutter is rising. Mark when it is fully open.",
+            Self::DropTilt => "Shutter is dropping. Mark when the slats are fully closed.",
+            Self::Drop => "Mark when the shutter reaches the bottom.",
+            Self::RiseTilt => "Shutter is rising. Mark when the slats are fully open.",
+            Self::Rise => "Mark when the shutter reaches the top.",
+            Self::Done => "Calibration finished.",
+        }
+    }
+}
+
+/// Output changes requested by the calibration: (output, on).
+pub type OutputRequests = Vec<(OutIdx, bool)>;
+
+/// Measurement of the shutter travel times. Drives the outputs directly
+/// (bypassing the firmware driver and its times) while the user marks the
+/// moments the shutter reaches end points.
+#[derive(Debug, Clone)]
+pub struct Calibration {
+    pub down: OutIdx,
+    pub up: OutIdx,
+    step: CalibrationStep,
+    /// Start of the current step.
+    since: Instant,
+    /// Measured durations in seconds.
+    drop_tilt: f32,
+    drop: f32,
+    rise_tilt: f32,
+    rise: f32,
+}
+
+impl Calibration {
+    /// Start calibration by rising the shutter to the top.
+    pub fn start(down: OutIdx, up: OutIdx, now: Instant) -> (Self, OutputRequests) {
+        let calibration = Self {
+            down,
+            up,
+            step: CalibrationStep::Homing,
+            since: now,
+            drop_tilt: 0.0,
+            drop: 0.0,
+            rise_tilt: 0.0,
+            rise: 0.0,
+        };
+        (calibration, vec![(down, false), (up, true)])
+    }
+
+    pub fn step(&self) -> CalibrationStep {
+        self.step
+    }
+
+    /// Time the current step should be marked by.
+    pub fn deadline(&self) -> Instant {
+        let default = Timings::default();
+        let limit = MAX_STEP_TRAVELS * default.rise.max(default.drop);
+        self.since + Duration::from_secs_f32(limit)
+    }
+
+    /// User marked the end of the current step.
+    pub fn mark(&mut self, now: Instant) -> OutputRequests {
+        let elapsed = now.saturating_duration_since(self.since).as_secs_f32();
+        self.since = now;
+        match self.step {
+            CalibrationStep::Homing => {
+                self.step = CalibrationStep::DropTilt;
+                vec![(self.up, false), (self.down, true)]
+            }
+            CalibrationStep::DropTilt => {
+                self.drop_tilt = elapsed;
+                self.step = CalibrationStep::Drop;
+                vec![]
+            }
+            CalibrationStep::Drop => {
+                self.drop = elapsed;
+                self.step = CalibrationStep::RiseTilt;
+                vec![(self.down, false), (self.up, true)]
+            }
+            CalibrationStep::RiseTilt => {
+                self.rise_tilt = elapsed;
+                self.step = CalibrationStep::Rise;
+                vec![]
+            }
+            CalibrationStep::Rise => {
+                self.rise = elapsed;
+                self.step = CalibrationStep::Done;
+                vec![(self.up, false)]
+            }
+            CalibrationStep::Done => vec![],
+        }
+    }
+
+    /// Stop the shutter and abandon calibration.
+    pub fn cancel(&self) -> OutputRequests {
+        vec![(self.down, false), (self.up, false)]
+    }
+
+    /// Measured timings, once done.
+    pub fn result(&self) -> Option<Timings> {
+        if self.step != CalibrationStep::Done {
+            return None;
+        }
+        Some(Timings {
+            rise: self.rise,
+            drop: self.drop,
+            tilt: (self.drop_tilt + self.rise_tilt) / 2.0,
+        })
+    }
+}
+
+/// Gate-side estimation of the shutter position, based on the state of its
+/// outputs and configured travel times.
+#[derive(Debug, Clone)]
+pub struct Tracker {
+    pub down: OutIdx,
+    pub up: OutIdx,
+    timings: Timings,
+
+    down_on: bool,
+    up_on: bool,
+    /// Position when the current movement started (or when it stopped).
+    position: Position,
+    /// Current movement: direction and start time.
+    movement: Option<(Direction, Instant)>,
+}
+
+impl Tracker {
+    pub fn new(down: OutIdx, up: OutIdx, timings: Timings) -> Self {
+        Self {
+            down,
+            up,
+            timings,
+            down_on: false,
+            up_on: false,
+            position: Position {
+                height: Estimate::Guessed(0.0),
+                tilt: Estimate::Guessed(0.0),
+            },
+            movement: None,
+        }
+    }
+
+    /// Estimated position at a given time.
+    pub fn position(&self, now: Instant) -> Position {
+        match self.movement {
+            Some((direction, since)) => {
+                let elapsed = now.saturating_duration_since(since).as_secs_f32();
+                self.position.advance(direction, elapsed, &self.timings)
+            }
+            None => self.position,
+        }
+    }
+
+    /// Replace travel times (eg. after calibration).
+    pub fn set_timings(&mut self, timings: Timings) {
+        self.timings = timings;
+    }
+
+    pub fn direction(&self) -> Option<Direction> {
+        self.movement.map(|(direction, _)| direction)
+    }
+
+    /// Follow configuration sent to the shutter driver. Zero times would
+    /// break the estimation and are ignored.
+    pub fn command(&mut self, cmd: &Cmd) {
+        let seconds = |millis: u16| (millis > 0).then_some(millis as f32 / 1000.0);
+        match *cmd {
+            Cmd::SetIO(down, up) => {
+                self.down = down;
+                self.up = up;
+            }
+            Cmd::SetRiseDropTime(rise, drop) => {
+                self.timings.rise = seconds(rise).unwrap_or(self.timings.rise);
+                self.timings.drop = seconds(drop).unwrap_or(self.timings.drop);
+            }
+            Cmd::SetTiltOverTime(tilt, _) => {
+                self.timings.tilt = seconds(tilt).unwrap_or(self.timings.tilt);
+            }
+            _ => {}
+        }
+    }
+
+    /// One of the shutter outputs changed its state. Returns true if the
+    /// output belongs to this shutter.
+    pub fn output_changed(&mut self, output: OutIdx, on: bool, now: Instant) -> bool {
+        if output == self.down {
+            self.down_on = on;
+        } else if output == self.up {
+            self.up_on = on;
+        } else {
+            return false;
+        }
+
+        // Settle the previous movement.
+        self.position = self.position(now);
+        self.movement = match (self.down_on, self.up_on) {
+            (true, false) => Some((Direction::Down, now)),
+            (false, true) => Some((Direction::Up, now)),
+            _ => None,
+        };
+        true
+    }
+}
+
+/// Trackers of all configured shutters, indexed by device address and shutter index.
+#[derive(Debug, Default)]
+pub struct Trackers {
+    trackers: HashMap<(u8, ShutterIdx), Tracker>,
+}
+
+impl Trackers {
+    pub fn from_config(config: &Config) -> Self {
+        let mut trackers = HashMap::new();
+        for device in config.devices.values() {
+            for shutter in device.shutters.values() {
+                let timings = shutter.timings();
+                let tracker = Tracker::new(shutter.down, shutter.up, timings);
+                trackers.insert((device.addr, shutter.id), tracker);
+            }
+        }
+        Self { trackers }
+    }
+
+    /// Follow a changed config. Estimates of shutters driven by the same
+    /// outputs are kept.
+    pub fn reconfigure(&mut self, config: &Config) {
+        let mut trackers = Self::from_config(config).trackers;
+        for (key, tracker) in trackers.iter_mut() {
+            match self.trackers.remove(key) {
+                Some(old) if old.down == tracker.down && old.up == tracker.up => {
+                    let timings = tracker.timings;
+                    *tracker = old;
+                    tracker.set_timings(timings);
+                }
+                _ => {}
+            }
+        }
+        self.trackers = trackers;
+    }
+
+    pub fn get(&self, addr: u8, shutter: ShutterIdx) -> Option<&Tracker> {
+        self.trackers.get(&(addr, shutter))
+    }
+
+    pub fn get_mut(&mut self, addr: u8, shutter: ShutterIdx) -> Option<&mut Tracker> {
+        self.trackers.get_mut(&(addr, shutter))
+    }
+
+    /// Update the shutter driven by the output. Returns its index if any.
+    pub fn output_changed(
+        &mut self,
+        addr: u8,
+        output: OutIdx,
+        on: bool,
+        now: Instant,
+    ) -> Option<ShutterIdx> {
+        self.trackers
+            .iter_mut()
+            .filter(|((device, _), _)| *device == addr)
+            .find_map(|((_, idx), tracker)| tracker.output_changed(output, on, now).then_some(*idx))
+    }
+
+    /// All shutters.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, ShutterIdx, &Tracker)> {
+        self.trackers
+            .iter()
+            .map(|((addr, idx), tracker)| (*addr, *idx, tracker))
+    }
+
+    /// Shutters currently in motion.
+    pub fn moving(&self) -> impl Iterator<Item = (u8, ShutterIdx, &Tracker)> {
+        self.trackers
+            .iter()
+            .filter(|(_, tracker)| tracker.direction().is_some())
+            .map(|((addr, idx), tracker)| (*addr, *idx, tracker))
+    }
+}
+
+/// Trackers shared between the tasks.
+pub type SharedTrackers = Arc<Mutex<Trackers>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMINGS: Timings = Timings {
+        rise: 10.0,
+        drop: 20.0,
+        tilt: 2.0,
+    };
+
+    fn guessed(height: f32, tilt: f32) -> Position {
+        Position {
+            height: Estimate::Guessed(height),
+            tilt: Estimate::Guessed(tilt),
+        }
+    }
+
+    #[test]
+    fn advance_tilts_first() {
+        let start = Position::new(50, 0);
+        let position = start.advance(Direction::Down, 1.0, &TIMINGS);
+        assert_eq!(position.height, Estimate::Known(50.0));
+        assert_eq!(position.tilt, Estimate::Known(50.0));
+
+        let position = start.advance(Direction::Down, 2.0 + 2.0, &TIMINGS);
+        assert_eq!(position.height, Estimate::Known(60.0));
+        assert_eq!(position.tilt, Estimate::Known(100.0));
+
+        let position = Position::new(50, 100).advance(Direction::Up, 2.0 + 1.0, &TIMINGS);
+        assert_eq!(position.height, Estimate::Known(40.0));
+        assert_eq!(position.tilt, Estimate::Known(0.0));
+    }
+
+    #[test]
+    fn advance_keeps_guess() {
+        let position = guessed(50.0, 50.0).advance(Direction::Down, 0.5, &TIMINGS);
+        assert_eq!(position, guessed(50.0, 75.0));
+
+        // The tilt end stop is reached, the height isn't.
+        let position = guessed(50.0, 50.0).advance(Direction::Down, 1.0 + 2.0, &TIMINGS);
+        assert_eq!(position.height, Estimate::Guessed(60.0));
+        assert_eq!(position.tilt, Estimate::Known(100.0));
+    }
+
+    #[test]
+    fn advance_clamps_at_end_stops() {
+        let position = guessed(90.0, 50.0).advance(Direction::Down, 60.0, &TIMINGS);
+        assert_eq!(position, Position::new(100, 100));
+
+        let position = guessed(10.0, 50.0).advance(Direction::Up, 60.0, &TIMINGS);
+        assert_eq!(position, Position::new(0, 0));
+
+        // Already there.
+        let position = Position::new(0, 0).advance(Direction::Up, 0.5, &TIMINGS);
+        assert_eq!(position, Position::new(0, 0));
+    }
+
+    fn trackers(addr: u8, shutter: ShutterIdx) -> Trackers {
+        let mut tracker = Tracker::new(1, 2, TIMINGS);
+        tracker.position = Position::new(0, 0);
+        Trackers {
+            trackers: HashMap::from([((addr, shutter), tracker)]),
+        }
+    }
+
+    #[test]
+    fn output_changed_tracks_movement() {
+        let start = Instant::now();
+        let at = |seconds: f32| start + Duration::from_secs_f32(seconds);
+        let mut trackers = trackers(5, 0);
+
+        // Not a shutter output, or another device.
+        assert_eq!(trackers.output_changed(5, 3, true, start), None);
+        assert_eq!(trackers.output_changed(6, 1, true, start), None);
+        assert_eq!(trackers.get(5, 0).unwrap().direction(), None);
+
+        assert_eq!(trackers.output_changed(5, 1, true, start), Some(0));
+        let tracker = trackers.get(5, 0).unwrap();
+        assert_eq!(tracker.direction(), Some(Direction::Down));
+        assert_eq!(tracker.position(at(1.0)).tilt, Estimate::Known(50.0));
+        assert_eq!(tracker.position(at(6.0)), Position::new(20, 100));
+
+        assert_eq!(trackers.output_changed(5, 1, false, at(6.0)), Some(0));
+        let tracker = trackers.get(5, 0).unwrap();
+        assert_eq!(tracker.direction(), None);
+        assert_eq!(tracker.position(at(100.0)), Position::new(20, 100));
+    }
+
+    #[test]
+    fn output_changed_reverses() {
+        let start = Instant::now();
+        let at = |seconds: f32| start + Duration::from_secs_f32(seconds);
+        let mut trackers = trackers(5, 0);
+
+        trackers.output_changed(5, 1, true, start);
+        trackers.output_changed(5, 1, false, at(6.0));
+        trackers.output_changed(5, 2, true, at(6.0));
+        let tracker = trackers.get(5, 0).unwrap();
+        assert_eq!(tracker.direction(), Some(Direction::Up));
+        assert_eq!(tracker.position(at(7.0)), Position::new(20, 50));
+        assert_eq!(tracker.position(at(9.0)), Position::new(10, 0));
+        assert_eq!(tracker.position(at(60.0)), Position::new(0, 0));
+
+        // Both outputs on means no movement.
+        trackers.output_changed(5, 1, true, at(9.0));
+        let tracker = trackers.get(5, 0).unwrap();
+        assert_eq!(tracker.direction(), None);
+        assert_eq!(tracker.position(at(60.0)), Position::new(10, 0));
+    }
+
+    #[test]
+    fn calibration_measures_steps() {
+        let start = Instant::now();
+        let at = |seconds: f32| start + Duration::from_secs_f32(seconds);
+
+        let (mut calibration, requests) = Calibration::start(1, 2, start);
+        assert_eq!(requests, vec![(1, false), (2, true)]);
+        assert_eq!(calibration.step(), CalibrationStep::Homing);
+        assert_eq!(calibration.result(), None);
+
+        // Homing time isn't measured.
+        assert_eq!(calibration.mark(at(30.0)), vec![(2, false), (1, true)]);
+        assert_eq!(calibration.step(), CalibrationStep::DropTilt);
+        assert_eq!(calibration.mark(at(32.0)), vec![]);
+        assert_eq!(calibration.step(), CalibrationStep::Drop);
+        assert_eq!(calibration.mark(at(52.0)), vec![(1, false), (2, true)]);
+        assert_eq!(calibration.step(), CalibrationStep::RiseTilt);
+        assert_eq!(calibration.mark(at(53.0)), vec![]);
+        assert_eq!(calibration.step(), CalibrationStep::Rise);
+        assert_eq!(calibration.result(), None);
+        assert_eq!(calibration.mark(at(63.0)), vec![(2, false)]);
+        assert_eq!(calibration.step(), CalibrationStep::Done);
+
+        let timings = calibration.result().unwrap();
+        assert_eq!(timings.drop, 20.0);
+        assert_eq!(timings.rise, 10.0);
+        assert_eq!(timings.tilt, 1.5);
+        assert_eq!(timings.check_measured(), Ok(()));
+
+        // Further marks change nothing.
+        assert_eq!(calibration.mark(at(70.0)), vec![]);
+        assert_eq!(calibration.result(), Some(timings));
+    }
+
+    #[test]
+    fn calibration_deadline_follows_step() {
+        let start = Instant::now();
+        let (mut calibration, _) = Calibration::start(1, 2, start);
+        let deadline = calibration.deadline();
+        assert!(deadline > start + Duration::from_secs(60));
+
+        calibration.mark(start + Duration::from_secs(10));
+        assert_eq!(calibration.deadline(), deadline + Duration::from_secs(10));
+        assert_eq!(calibration.cancel(), vec![(1, false), (2, false)]);
+    }
+
+    #[test]
+    fn check_measured_rejects_short_times() {
+        assert_eq!(TIMINGS.check_measured(), Ok(()));
+        let short = Timings {
+            tilt: 0.1,
+            ..TIMINGS
+        };
+        assert!(short.check_measured().unwrap_err().contains("tilt"));
+        let zero = Timings {
+            rise: 0.0,
+            ..TIMINGS
+        };
+        assert!(zero.check_measured().unwrap_err().contains("rise"));
+        let nan = Timings {
+            drop: f32::NAN,
+            ..TIMINGS
+        };
+        assert!(nan.check_measured().is_err());
+        let long = Timings {
+            drop: 70.0,
+            ..TIMINGS
+        };
+        assert!(long.check_measured().unwrap_err().contains("drop"));
+    }
+
+    #[test]
+    fn command_follows_driver_config() {
+        let mut tracker = Tracker::new(1, 2, TIMINGS);
+        tracker.command(&Cmd::SetRiseDropTime(30000, 0));
+        tracker.command(&Cmd::SetTiltOverTime(1500, 500));
+        tracker.command(&Cmd::SetIO(3, 4));
+        tracker.command(&Cmd::Open);
+        assert_eq!(
+            tracker.timings,
+            Timings {
+                rise: 30.0,
+                drop: 20.0,
+                tilt: 1.5
+            }
+        );
+        assert_eq!((tracker.down, tracker.up), (3, 4));
     }
 }
@@ -0,0 +1,73 @@
+use crate::consts::{InIdx, OutIdx};
+use crate::homeassistant::Outgoing;
+use std::collections::HashMap;
+
+/// Last reported device status.
+#[derive(Debug, Clone, Copy)]
+struct DeviceStatus {
+    uptime: u32,
+    errors: u16,
+    warnings: u16,
+}
+
+/// Last known state of all devices, kept to restore HA state after it
+/// restarts. IO state is None when the device reported it as unknown.
+#[derive(Debug, Default)]
+pub struct StateCache {
+    outputs: HashMap<(u8, OutIdx), Option<bool>>,
+    inputs: HashMap<(u8, InIdx), Option<bool>>,
+    status: HashMap<u8, DeviceStatus>,
+}
+
+impl StateCache {
+    pub fn set_output(&mut self, device: u8, output: OutIdx, on: Option<bool>) {
+        self.outputs.insert((device, output), on);
+    }
+
+    pub fn output(&self, device: u8, output: OutIdx) -> Option<bool> {
+        self.outputs.get(&(device, output)).copied().flatten()
+    }
+
+    /// Apply a toggle reported by the device. The result is unknown unless
+    /// the previous state was known.
+    pub fn toggle_output(&mut self, device: u8, output: OutIdx) -> Option<bool> {
+        let on = self.output(device, output).map(|on| !on);
+        self.set_output(device, output, on);
+        on
+    }
+
+    pub fn set_input(&mut self, device: u8, input: InIdx, on: Option<bool>) {
+        self.inputs.insert((device, input), on);
+    }
+
+    pub fn set_status(&mut self, device: u8, uptime: u32, errors: u16, warnings: u16) {
+        let status = DeviceStatus {
+            uptime,
+            errors,
+            warnings,
+        };
+        self.status.insert(device, status);
+    }
+
+    /// Messages publishing all the known state.
+    pub fn snapshot(&self) -> Vec<Outgoing> {
+        let outputs = self
+            .outputs
+            .iter()
+            .map(|(&(device, output), &on)| Outgoing::OutputChanged { device, output, on });
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|(&(device, input), &on)| Outgoing::InputChanged { device, input, on });
+        let status = self
+            .status
+            .iter()
+            .map(|(&device, status)| Outgoing::DeviceStatus {
+                device,
+                uptime: status.uptime,
+                errors: status.errors,
+                warnings: status.warnings,
+            });
+        outputs.chain(inputs).chain(status).collect()
+    }
+}